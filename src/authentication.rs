@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::fs;
+
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::middleware::Next;
+use actix_web::{FromRequest, HttpMessage, HttpRequest};
+use futures::future::{ready, Ready};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::environment::{
+    API_KEYS, JWT_AUDIENCE, JWT_ISSUER, JWT_PUBLIC_KEY_FILE, JWT_SECRET, SERVICE_TOKEN,
+};
+use crate::errors::{Error, Result};
+
+static JWT_KEYS: OnceCell<Vec<(Algorithm, DecodingKey)>> = OnceCell::new();
+
+/// Loads the keys JWTs are verified with, failing if the public key cannot be read.
+pub fn load_keys() -> std::io::Result<()> {
+    let mut keys = Vec::new();
+    if let Some(secret) = JWT_SECRET.as_ref() {
+        keys.push((
+            Algorithm::HS256,
+            DecodingKey::from_secret(secret.as_bytes()),
+        ));
+    }
+    if let Some(path) = JWT_PUBLIC_KEY_FILE.as_ref() {
+        let pem = fs::read(path)?;
+        keys.push((
+            Algorithm::RS256,
+            DecodingKey::from_rsa_pem(&pem).map_err(std::io::Error::other)?,
+        ));
+    }
+    if JWT_KEYS.set(keys).is_err() {
+        panic!("Failed to set JWT keys");
+    }
+    Ok(())
+}
+
+/// Compares two byte strings in constant time with respect to their contents.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[derive(Clone, Debug)]
+pub enum Credential {
+    /// The `SERVICE_TOKEN`, held by trusted services.
+    Service,
+    /// One of the static `API_KEYS`.
+    ApiKey,
+    /// A signed JWT, limited to the scopes and claims it carries.
+    Token {
+        scopes: Vec<String>,
+        claims: HashMap<String, Value>,
+    },
+}
+
+/// The authenticated caller of a request.
+#[derive(Clone, Debug)]
+pub struct Identity {
+    pub subject: String,
+    pub credential: Credential,
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+    /// Space separated scopes, as issued by OAuth 2.0 servers.
+    #[serde(default)]
+    scope: Option<String>,
+    #[serde(default)]
+    scopes: Vec<String>,
+    #[serde(flatten)]
+    claims: HashMap<String, Value>,
+}
+
+fn verify_jwt(token: &str) -> Result<Identity> {
+    let algorithm = decode_header(token).map_err(|_| Error::Unauthorized)?.alg;
+    let (_, key) = JWT_KEYS
+        .get()
+        .ok_or(Error::Unauthorized)?
+        .iter()
+        .find(|(candidate, _)| *candidate == algorithm)
+        .ok_or(Error::Unauthorized)?;
+    let mut validation = Validation::new(algorithm);
+    validation.set_required_spec_claims(&["exp", "sub"]);
+    if let Some(issuer) = JWT_ISSUER.as_ref() {
+        validation.set_issuer(&[issuer]);
+    }
+    match JWT_AUDIENCE.as_ref() {
+        Some(audience) => validation.set_audience(&[audience]),
+        None => validation.validate_aud = false,
+    }
+    let claims = decode::<Claims>(token, key, &validation)
+        .map_err(|_| Error::Unauthorized)?
+        .claims;
+    let mut scopes = claims.scopes;
+    if let Some(scope) = claims.scope {
+        scopes.extend(scope.split_whitespace().map(str::to_string));
+    }
+    Ok(Identity {
+        subject: claims.sub,
+        credential: Credential::Token {
+            scopes,
+            claims: claims.claims,
+        },
+    })
+}
+
+fn verify(token: &str) -> Result<Identity> {
+    if SERVICE_TOKEN
+        .as_ref()
+        .is_some_and(|expected| constant_time_eq(token.as_bytes(), expected.as_bytes()))
+    {
+        return Ok(Identity {
+            subject: "service".to_string(),
+            credential: Credential::Service,
+        });
+    }
+    // Compare against every key so the time taken does not reveal which one matched.
+    let api_key = API_KEYS.iter().fold(None, |found, (subject, key)| {
+        if constant_time_eq(token.as_bytes(), key.as_bytes()) {
+            Some(subject)
+        } else {
+            found
+        }
+    });
+    if let Some(subject) = api_key {
+        return Ok(Identity {
+            subject: subject.clone(),
+            credential: Credential::ApiKey,
+        });
+    }
+    if token.split('.').count() == 3 {
+        return verify_jwt(token);
+    }
+    Err(Error::Unauthorized)
+}
+
+/// Middleware resolving the bearer token of the request into an `Identity`, rejecting the
+/// request if the token is invalid. Requests without a token pass through anonymously.
+pub async fn authenticate(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> std::result::Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if let Some(token) = token {
+        match verify(token) {
+            Ok(identity) => {
+                req.extensions_mut().insert(identity);
+            }
+            // Respond here rather than failing, so outer middleware still sees a response.
+            Err(error) => return Ok(req.error_response(error).map_into_right_body()),
+        }
+    }
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+impl FromRequest for Identity {
+    type Error = Error;
+    type Future = Ready<Result<Identity>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<Identity>()
+                .cloned()
+                .ok_or(Error::Unauthorized),
+        )
+    }
+}
+
+/// Extractor for requests made by a trusted service, authenticated with the `SERVICE_TOKEN`
+/// bearer token.
+pub struct Service;
+
+impl FromRequest for Service {
+    type Error = Error;
+    type Future = Ready<Result<Service>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(match req.extensions().get::<Identity>() {
+            Some(Identity {
+                credential: Credential::Service,
+                ..
+            }) => Ok(Service),
+            _ => Err(Error::Unauthorized),
+        })
+    }
+}
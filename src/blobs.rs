@@ -0,0 +1,177 @@
+use std::path::Path;
+use std::time::Duration;
+
+use async_std::stream::StreamExt;
+use async_std::task;
+use log::warn;
+
+use mongodb::{bson::doc, Collection};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    database::{is_duplicate_key, DATABASE},
+    environment::MONGODB_DATABASE,
+    errors::{Error, Result},
+    storage::get_backend,
+};
+
+/// Attempts to take a reference to a blob before giving up on it.
+const MAX_ATTEMPTS: u32 = 5;
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+
+pub fn get_collection() -> Collection<Blob> {
+    DATABASE
+        .get()
+        .expect("Failed to get MongoDB client")
+        .database(&MONGODB_DATABASE)
+        .collection("blobs")
+}
+
+/// Contents stored once per store under their hash, shared by every file with those contents.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Blob {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub store: String,
+    pub key: String,
+    pub references: i64,
+    /// Set while the collector deletes the contents, after which the blob is gone.
+    #[serde(default)]
+    pub deleting: bool,
+}
+
+impl Blob {
+    /// Key of the contents with the given hash. Backends can be shared between stores, so
+    /// each store keeps its own copy to match the references it counts.
+    pub fn key(store_id: &str, hash: &str) -> String {
+        format!("blobs/{}/{}", store_id, hash)
+    }
+
+    /// Id of the record counting references to the blob under `key`. Keying records by id
+    /// keeps concurrent first uploads from creating two.
+    pub fn id(store_id: &str, key: &str) -> String {
+        format!("{}/{}", store_id, key)
+    }
+
+    /// Adds a reference to the blob with the given hash, storing the contents at `source`
+    /// unless the store already holds them, and returns its key.
+    pub async fn acquire(store_id: &str, hash: &str, source: &Path) -> Result<String> {
+        let key = Blob::key(store_id, hash);
+        let id = Blob::id(store_id, &key);
+        let mut previous = None;
+        for attempt in 1..=MAX_ATTEMPTS {
+            // A blob being deleted is never revived, so the upsert collides with its record
+            // until the collector is done with it.
+            match get_collection()
+                .find_one_and_update(
+                    doc! { "_id": &id, "deleting": { "$ne": true } },
+                    doc! {
+                        "$inc": { "references": 1 },
+                        "$setOnInsert": { "store": store_id, "key": &key },
+                    },
+                )
+                .upsert(true)
+                .await
+            {
+                Ok(blob) => {
+                    previous = Some(blob);
+                    break;
+                }
+                // Another upload created the record first, or the collector is deleting it.
+                Err(error) if attempt < MAX_ATTEMPTS && is_duplicate_key(&error) => {
+                    task::sleep(RETRY_DELAY * attempt).await;
+                }
+                Err(_) => return Err(Error::DatabaseError),
+            }
+        }
+        let previous = previous.ok_or(Error::DatabaseError)?;
+        let backend = get_backend(store_id)?;
+        // An earlier upload of the same contents may have failed before storing them.
+        if previous.is_none() || backend.head(&key).await?.is_none() {
+            if let Err(error) = backend.put_file(&key, source).await {
+                Blob::release(store_id, &key).await.ok();
+                return Err(error);
+            }
+        }
+        Ok(key)
+    }
+
+    /// Drops a reference to the blob. Blobs nothing references are left for the collector,
+    /// so that a new upload of the same contents can still take them over.
+    pub async fn release(store_id: &str, key: &str) -> Result<()> {
+        get_collection()
+            .update_one(
+                doc! { "_id": Blob::id(store_id, key) },
+                doc! { "$inc": { "references": -1 } },
+            )
+            .await
+            .map_err(|_| Error::DatabaseError)?;
+        Ok(())
+    }
+
+    /// Deletes the contents and records of blobs nothing references, returning how many were
+    /// deleted.
+    pub async fn collect() -> Result<u64> {
+        let mut cursor = get_collection()
+            .find(doc! { "references": { "$lte": 0 } })
+            .await
+            .map_err(|_| Error::DatabaseError)?;
+        let mut count = 0;
+        while let Some(blob) = cursor.next().await {
+            let blob = blob.map_err(|_| Error::DatabaseError)?;
+            let Ok(backend) = get_backend(&blob.store) else {
+                continue;
+            };
+            // Marking the blob first keeps uploads from taking a reference while its contents
+            // are deleted. A blob that was marked earlier but not deleted is marked again.
+            let result = get_collection()
+                .update_one(
+                    doc! { "_id": &blob.id, "references": { "$lte": 0 } },
+                    doc! { "$set": { "deleting": true } },
+                )
+                .await
+                .map_err(|_| Error::DatabaseError)?;
+            if result.matched_count == 0 {
+                continue;
+            }
+            if backend.delete(&blob.key).await.is_err() {
+                warn!("Failed to delete blob {}", blob.key);
+                continue;
+            }
+            get_collection()
+                .delete_one(doc! { "_id": &blob.id, "deleting": true })
+                .await
+                .map_err(|_| Error::DatabaseError)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_blobs_by_store_and_hash() {
+        assert_eq!(Blob::key("avatars", "abc"), "blobs/avatars/abc");
+        assert_ne!(Blob::key("avatars", "abc"), Blob::key("banners", "abc"));
+        assert_eq!(
+            Blob::id("avatars", &Blob::key("avatars", "abc")),
+            "avatars/blobs/avatars/abc"
+        );
+    }
+
+    #[test]
+    fn reads_records_without_deletion_mark() {
+        let blob: Blob = mongodb::bson::from_document(doc! {
+            "_id": "avatars/blobs/avatars/abc",
+            "store": "avatars",
+            "key": "blobs/avatars/abc",
+            "references": 2_i64,
+        })
+        .unwrap();
+        assert_eq!(blob.references, 2);
+        assert!(!blob.deleting);
+    }
+}
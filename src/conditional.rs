@@ -0,0 +1,168 @@
+use std::time::{Duration, UNIX_EPOCH};
+
+use actix_web::http::header::{
+    EntityTag, HttpDate, IfModifiedSince, IfNoneMatch, IfRange, LastModified, ETAG,
+};
+use actix_web::{HttpMessage, HttpRequest, HttpResponseBuilder};
+
+use crate::files::File;
+
+/// Builds the entity tag of the file, or of one of its encoded variants.
+pub fn etag(file: &File, variant: Option<&str>) -> Option<EntityTag> {
+    let hash = file.hash.as_ref()?;
+    Some(EntityTag::new_strong(match variant {
+        Some(variant) => format!("{}-{}", hash, variant),
+        None => hash.clone(),
+    }))
+}
+
+pub fn last_modified(file: &File) -> Option<HttpDate> {
+    // HTTP dates only have second precision, so drop the milliseconds before comparing.
+    let seconds = file.uploaded_at?.timestamp_millis().div_euclid(1000);
+    Some(HttpDate::from(
+        UNIX_EPOCH + Duration::from_secs(seconds.try_into().ok()?),
+    ))
+}
+
+pub fn insert_validators(file: &File, variant: Option<&str>, response: &mut HttpResponseBuilder) {
+    if let Some(etag) = etag(file, variant) {
+        response.insert_header((ETAG, etag));
+    }
+    if let Some(date) = last_modified(file) {
+        response.insert_header(LastModified(date));
+    }
+}
+
+pub fn is_not_modified(req: &HttpRequest, file: &File, variant: Option<&str>) -> bool {
+    if let Some(condition) = req.get_header::<IfNoneMatch>() {
+        return match (condition, etag(file, variant)) {
+            (IfNoneMatch::Any, _) => true,
+            (IfNoneMatch::Items(tags), Some(etag)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+            (IfNoneMatch::Items(_), None) => false,
+        };
+    }
+    match (req.get_header::<IfModifiedSince>(), last_modified(file)) {
+        (Some(IfModifiedSince(since)), Some(date)) => date <= since,
+        _ => false,
+    }
+}
+
+/// Whether a `Range` request may be honoured, which is only the case if the client's copy of
+/// the file named in `If-Range` is still current.
+pub fn is_range_fresh(req: &HttpRequest, file: &File) -> bool {
+    match req.get_header::<IfRange>() {
+        None => true,
+        Some(IfRange::EntityTag(tag)) => etag(file, None).is_some_and(|etag| tag.strong_eq(&etag)),
+        Some(IfRange::Date(date)) => last_modified(file).is_some_and(|modified| modified == date),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::{HeaderName, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE};
+    use actix_web::test::TestRequest;
+    use mongodb::bson::DateTime;
+
+    use super::*;
+    use crate::files::FileMetadata;
+
+    fn file() -> File {
+        File {
+            id: "01J00000000000000000000000".to_string(),
+            store: "attachments".to_string(),
+            filename: "file.bin".to_string(),
+            metadata: FileMetadata::File,
+            content_type: "application/octet-stream".to_string(),
+            size: 100,
+            attached: true,
+            deleted: false,
+            flagged: false,
+            object_id: None,
+            hash: Some("abc".to_string()),
+            // The milliseconds are lost in HTTP dates.
+            uploaded_at: Some(DateTime::from_millis(1_700_000_000_500)),
+            blob: None,
+            uploader: None,
+        }
+    }
+
+    fn request(header: (HeaderName, &str)) -> HttpRequest {
+        TestRequest::default()
+            .insert_header(header)
+            .to_http_request()
+    }
+
+    #[test]
+    fn matches_entity_tags() {
+        let file = file();
+        assert!(is_not_modified(
+            &request((IF_NONE_MATCH, "\"abc\"")),
+            &file,
+            None
+        ));
+        assert!(is_not_modified(
+            &request((IF_NONE_MATCH, "W/\"other\", W/\"abc\"")),
+            &file,
+            None
+        ));
+        assert!(is_not_modified(&request((IF_NONE_MATCH, "*")), &file, None));
+        assert!(!is_not_modified(
+            &request((IF_NONE_MATCH, "\"abc\"")),
+            &file,
+            Some("webp")
+        ));
+        assert!(is_not_modified(
+            &request((IF_NONE_MATCH, "\"abc-webp\"")),
+            &file,
+            Some("webp")
+        ));
+    }
+
+    #[test]
+    fn prefers_entity_tags_over_dates() {
+        let req = TestRequest::default()
+            .insert_header((IF_NONE_MATCH, "\"other\""))
+            .insert_header((IF_MODIFIED_SINCE, "Tue, 14 Nov 2023 22:13:21 GMT"))
+            .to_http_request();
+        assert!(!is_not_modified(&req, &file(), None));
+    }
+
+    #[test]
+    fn compares_modification_dates() {
+        let file = file();
+        for (since, expected) in [
+            ("Tue, 14 Nov 2023 22:13:19 GMT", false),
+            ("Tue, 14 Nov 2023 22:13:20 GMT", true),
+            ("Tue, 14 Nov 2023 22:13:21 GMT", true),
+        ] {
+            let req = request((IF_MODIFIED_SINCE, since));
+            assert_eq!(is_not_modified(&req, &file, None), expected, "{}", since);
+        }
+        assert!(!is_not_modified(
+            &TestRequest::default().to_http_request(),
+            &file,
+            None
+        ));
+    }
+
+    #[test]
+    fn honours_ranges_of_current_copies() {
+        let file = file();
+        assert!(is_range_fresh(
+            &TestRequest::default().to_http_request(),
+            &file
+        ));
+        assert!(is_range_fresh(&request((IF_RANGE, "\"abc\"")), &file));
+        assert!(is_range_fresh(
+            &request((IF_RANGE, "Tue, 14 Nov 2023 22:13:20 GMT")),
+            &file
+        ));
+        // Weak tags and dates other than the exact one never match.
+        assert!(!is_range_fresh(&request((IF_RANGE, "W/\"abc\"")), &file));
+        assert!(!is_range_fresh(&request((IF_RANGE, "\"other\"")), &file));
+        assert!(!is_range_fresh(
+            &request((IF_RANGE, "Tue, 14 Nov 2023 22:13:21 GMT")),
+            &file
+        ));
+    }
+}
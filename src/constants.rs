@@ -1,3 +1,5 @@
 pub const CACHE_CONTROL: &str = "public, max-age=604800, must-revalidate";
 pub const SERVICE: &str = "cdn";
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const USER_AGENT: &str =
+    "Mozilla/5.0 (compatible; NextflowCDN/1.0; +https://github.com/Nextflow-Cloud/cdn)";
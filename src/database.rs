@@ -1,8 +1,11 @@
 use crate::environment::MONGODB_URI;
 
+use mongodb::error::{Error, ErrorKind, WriteFailure};
 use mongodb::Client;
 use once_cell::sync::OnceCell;
 
+const DUPLICATE_KEY: i32 = 11000;
+
 pub static DATABASE: OnceCell<Client> = OnceCell::new();
 
 pub async fn connect() {
@@ -11,3 +14,11 @@ pub async fn connect() {
         .expect("Failed to connect to MongoDB");
     DATABASE.set(client).expect("Failed to set MongoDB client");
 }
+
+/// Whether a write failed because a document with the same unique key already exists.
+pub fn is_duplicate_key(error: &Error) -> bool {
+    matches!(
+        error.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(error)) if error.code == DUPLICATE_KEY
+    )
+}
@@ -1,8 +1,11 @@
 use crate::errors::{Error, Result};
+use crate::ratelimit::RateLimit;
 
 use lazy_static::lazy_static;
 use s3::{creds::Credentials, Region};
 use std::env;
+use std::thread::available_parallelism;
+use std::time::Duration;
 
 lazy_static! {
     pub static ref STORES: String =
@@ -14,14 +17,69 @@ lazy_static! {
         env::var("MONGODB_DATABASE").unwrap_or_else(|_| "cdn".to_string());
     pub static ref LOCAL_STORAGE_PATH: String =
         env::var("LOCAL_STORAGE_PATH").unwrap_or_else(|_| "./files".to_string());
+    pub static ref UPLOAD_PATH: String =
+        env::var("UPLOAD_PATH").unwrap_or_else(|_| "./uploads".to_string());
     pub static ref S3_REGION: Region = Region::Custom {
         region: env::var("S3_REGION").unwrap_or_else(|_| String::new()),
         endpoint: env::var("S3_ENDPOINT").unwrap_or_else(|_| String::new())
     };
     pub static ref S3_CREDENTIALS: Credentials =
         Credentials::default().expect("Failed to get S3 credentials");
+    pub static ref SERVICE_TOKEN: Option<String> = env::var("SERVICE_TOKEN").ok();
+    /// Key signing URLs to files in private stores.
+    pub static ref URL_SIGNING_SECRET: Option<String> = env::var("URL_SIGNING_SECRET").ok();
+    pub static ref JWT_SECRET: Option<String> = env::var("JWT_SECRET").ok();
+    pub static ref JWT_PUBLIC_KEY_FILE: Option<String> = env::var("JWT_PUBLIC_KEY_FILE").ok();
+    pub static ref JWT_ISSUER: Option<String> = env::var("JWT_ISSUER").ok();
+    pub static ref JWT_AUDIENCE: Option<String> = env::var("JWT_AUDIENCE").ok();
+    /// Static API keys as comma separated `subject:key` pairs.
+    pub static ref API_KEYS: Vec<(String, String)> = env::var("API_KEYS")
+        .map(|v| {
+            v.split(',')
+                .filter_map(|pair| pair.trim().split_once(':'))
+                .map(|(subject, key)| (subject.to_string(), key.to_string()))
+                .collect()
+        })
+        .unwrap_or_default();
     pub static ref USE_S3: bool =
         env::var("CDN_S3_REGION").is_ok() && env::var("CDN_S3_ENDPOINT").is_ok();
+    pub static ref TRANSFORM_CONCURRENCY: usize = env::var("TRANSFORM_CONCURRENCY")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_else(|| available_parallelism().map(|n| n.get()).unwrap_or(1));
+    pub static ref GC_INTERVAL: Duration = Duration::from_secs(
+        env::var("GC_INTERVAL")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(900)
+    );
+    pub static ref RECONCILE_INTERVAL: Option<Duration> = env::var("RECONCILE_INTERVAL")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_secs);
+    pub static ref RECONCILE_DRY_RUN: bool = env::var("RECONCILE_DRY_RUN")
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(false);
+    /// Limits as `requests/seconds`, or `off` to lift them.
+    pub static ref RATE_LIMIT_UPLOAD: Option<RateLimit> = RateLimit::parse(
+        &env::var("RATE_LIMIT_UPLOAD").unwrap_or_else(|_| "60/60".to_string())
+    );
+    pub static ref RATE_LIMIT_EMBED: Option<RateLimit> = RateLimit::parse(
+        &env::var("RATE_LIMIT_EMBED").unwrap_or_else(|_| "60/60".to_string())
+    );
+    pub static ref RATE_LIMIT_PROXY: Option<RateLimit> = RateLimit::parse(
+        &env::var("RATE_LIMIT_PROXY").unwrap_or_else(|_| "300/60".to_string())
+    );
+    /// Whether a proxy appends the address of callers to `X-Forwarded-For`.
+    pub static ref TRUST_PROXY: bool = env::var("TRUST_PROXY")
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(false);
+    pub static ref TRANSFORM_QUEUE_TIMEOUT: Duration = Duration::from_millis(
+        env::var("TRANSFORM_QUEUE_TIMEOUT")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(5000)
+    );
 }
 
 pub fn get_s3_bucket(bucket: &str) -> Result<s3::Bucket> {
@@ -1,24 +1,57 @@
+use actix_web::http::header::RETRY_AFTER;
 use actix_web::http::StatusCode;
 use actix_web::{HttpResponse, ResponseError};
 use serde::Serialize;
 use std::fmt::{Display, Formatter};
 
+/// What a quota that was exceeded limits.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaKind {
+    Bytes,
+    Files,
+}
+
 #[derive(Debug, Serialize)]
 #[serde(tag = "error", rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum Error {
-    FileTooLarge { max_size: usize },
+    FileTooLarge {
+        max_size: usize,
+    },
+    TooManyFiles {
+        max_files: usize,
+    },
+    RequestTooLarge {
+        max_size: usize,
+    },
+    QuotaExceeded {
+        kind: QuotaKind,
+        used: u64,
+        limit: u64,
+    },
     FileTypeNotAllowed,
+    ImageTooLarge,
+    OffsetMismatch,
+    UploadLocked,
+    UnsupportedVersion,
 
     InvalidData,
     MissingData,
+    Unauthorized,
+    Forbidden,
 
     DatabaseError,
     UnknownStore,
     UnknownError,
+    NotSupported,
 
     NotFound,
     ProcessingError,
     StorageError,
+    Overloaded,
+    RateLimited {
+        retry_after: u64,
+    },
 
     MetaParseFailed,
     MissingContentType,
@@ -38,18 +71,30 @@ impl ResponseError for Error {
     fn status_code(&self) -> StatusCode {
         match &self {
             Error::FileTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::TooManyFiles { .. } => StatusCode::BAD_REQUEST,
+            Error::RequestTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::QuotaExceeded { .. } => StatusCode::PAYLOAD_TOO_LARGE,
             Error::FileTypeNotAllowed => StatusCode::BAD_REQUEST,
+            Error::ImageTooLarge => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::OffsetMismatch => StatusCode::CONFLICT,
+            Error::UploadLocked => StatusCode::LOCKED,
+            Error::UnsupportedVersion => StatusCode::PRECONDITION_FAILED,
 
             Error::InvalidData => StatusCode::BAD_REQUEST,
             Error::MissingData => StatusCode::BAD_REQUEST,
+            Error::Unauthorized => StatusCode::UNAUTHORIZED,
+            Error::Forbidden => StatusCode::FORBIDDEN,
 
             Error::DatabaseError => StatusCode::INTERNAL_SERVER_ERROR,
             Error::UnknownStore => StatusCode::BAD_REQUEST,
             Error::UnknownError => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::NotSupported => StatusCode::BAD_REQUEST,
 
             Error::NotFound => StatusCode::NOT_FOUND,
             Error::ProcessingError => StatusCode::INTERNAL_SERVER_ERROR,
             Error::StorageError => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::Overloaded => StatusCode::SERVICE_UNAVAILABLE,
+            Error::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
 
             Error::MetaParseFailed => StatusCode::INTERNAL_SERVER_ERROR,
             Error::MissingContentType => StatusCode::BAD_REQUEST,
@@ -61,7 +106,11 @@ impl ResponseError for Error {
     }
 
     fn error_response(&self) -> HttpResponse {
-        HttpResponse::build(self.status_code()).json(self)
+        let mut response = HttpResponse::build(self.status_code());
+        if let Error::RateLimited { retry_after } = self {
+            response.insert_header((RETRY_AFTER, retry_after.to_string()));
+        }
+        response.json(self)
     }
 }
 
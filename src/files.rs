@@ -1,17 +1,27 @@
-use std::{cmp::min, path::PathBuf, sync::Arc};
+use std::time::{Duration, SystemTime};
 
-use futures::AsyncReadExt;
-use mongodb::{bson::doc, Collection};
+use futures::TryStreamExt;
+use log::warn;
+use mongodb::{
+    bson::{doc, DateTime},
+    Collection,
+};
 use serde::{Deserialize, Serialize};
 
 use crate::{
     database::DATABASE,
-    environment::{get_s3_bucket, LOCAL_STORAGE_PATH, MONGODB_DATABASE, USE_S3},
+    environment::MONGODB_DATABASE,
     errors::{Error, Result},
     routes::serve::Resize,
-    utilities::try_resize,
+    storage::{get_backend, ByteStream},
+    stores::Store,
+    transform::{self, Transform},
 };
 
+/// Most resized copies kept per file. Other sizes are still served, just not kept, so that
+/// requesting every possible size cannot fill the store.
+const MAX_VARIANTS: usize = 32;
+
 pub fn get_collection() -> Collection<File> {
     DATABASE
         .get()
@@ -42,122 +52,213 @@ pub struct File {
     pub attached: bool,
     pub deleted: bool,
     pub flagged: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub object_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uploaded_at: Option<DateTime>,
+    /// Key of the shared blob holding the contents, for files stored by hash.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blob: Option<String>,
+    /// Subject of the authenticated caller who uploaded the file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uploader: Option<String>,
+}
+
+/// Narrows down the files listed for an uploader.
+#[derive(Debug, Default)]
+pub struct FileFilter {
+    pub store: Option<String>,
+    /// Type of the metadata of the file, such as `IMAGE`.
+    pub file_type: Option<String>,
+    pub attached: Option<bool>,
 }
 
 impl File {
-    pub async fn delete_in_storage(&self) -> Result<()> {
-        if *USE_S3 {
-            let bucket = get_s3_bucket(&self.store)?;
-            let response = bucket
-                .delete_object(format!("/{}", &self.id))
-                .await
-                .map_err(|_| Error::ProcessingError)?;
-            if response.status_code() != 200 {
-                return Err(Error::ProcessingError);
-            }
-        } else {
-            let path = format!("{}/{}", *LOCAL_STORAGE_PATH, &self.id);
-            async_std::fs::remove_file(path)
-                .await
-                .map_err(|_| Error::ProcessingError)?
+    /// Key of the object holding the contents of the file.
+    pub fn key(&self) -> &str {
+        self.blob.as_deref().unwrap_or(&self.id)
+    }
+
+    /// Lists the keys of the objects owned by the file alone, which are its cached variants
+    /// and, unless it is stored as a shared blob, the original.
+    pub async fn storage_keys(&self) -> Result<Vec<String>> {
+        let backend = get_backend(&self.store)?;
+        let mut keys = backend
+            .list(&self.variant_prefix())
+            .await?
+            .into_iter()
+            .map(|variant| variant.key)
+            .collect::<Vec<_>>();
+        if self.blob.is_none() {
+            keys.push(self.id.clone());
         }
-        Ok(())
+        Ok(keys)
     }
 
-    pub async fn delete(self) -> Result<()> {
-        get_collection()
-            .delete_one(doc! { "id": &self.id })
+    /// Attaches unattached files to the object owning them, returning how many matched.
+    pub async fn attach(store_id: &str, ids: &[String], object_id: &str) -> Result<u64> {
+        let result = get_collection()
+            .update_many(
+                doc! {
+                    "id": { "$in": ids },
+                    "store": store_id,
+                    "deleted": false,
+                    "$or": [{ "attached": false }, { "objectId": object_id }],
+                },
+                doc! { "$set": { "attached": true, "objectId": object_id } },
+            )
             .await
             .map_err(|_| Error::DatabaseError)?;
-        self.delete_in_storage().await?;
-        Ok(())
+        Ok(result.matched_count)
     }
 
-    pub async fn find(id: &str, store_id: &String) -> Result<File> {
+    pub async fn detach(store_id: &str, ids: &[String]) -> Result<u64> {
+        let result = get_collection()
+            .update_many(
+                doc! { "id": { "$in": ids }, "store": store_id, "deleted": false },
+                doc! { "$set": { "attached": false }, "$unset": { "objectId": "" } },
+            )
+            .await
+            .map_err(|_| Error::DatabaseError)?;
+        Ok(result.matched_count)
+    }
+
+    /// Soft-deletes files, leaving the removal from storage to the background task.
+    pub async fn mark_deleted(store_id: &str, ids: &[String]) -> Result<u64> {
+        let result = get_collection()
+            .update_many(
+                doc! { "id": { "$in": ids }, "store": store_id },
+                doc! { "$set": { "deleted": true } },
+            )
+            .await
+            .map_err(|_| Error::DatabaseError)?;
+        Ok(result.matched_count)
+    }
+
+    /// Soft-deletes every file uploaded by `uploader` across all stores.
+    pub async fn mark_deleted_by_uploader(uploader: &str) -> Result<u64> {
+        let result = get_collection()
+            .update_many(
+                doc! { "uploader": uploader, "deleted": false },
+                doc! { "$set": { "deleted": true } },
+            )
+            .await
+            .map_err(|_| Error::DatabaseError)?;
+        Ok(result.modified_count)
+    }
+
+    /// Lists up to `limit` files uploaded by `uploader` in the order they were uploaded,
+    /// starting after the file with the id `after`.
+    pub async fn list_by_uploader(
+        uploader: &str,
+        filter: &FileFilter,
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<File>> {
+        let mut query = doc! { "uploader": uploader, "deleted": false };
+        if let Some(store) = &filter.store {
+            query.insert("store", store);
+        }
+        if let Some(file_type) = &filter.file_type {
+            query.insert("metadata.type", file_type.to_uppercase());
+        }
+        if let Some(attached) = filter.attached {
+            query.insert("attached", attached);
+        }
+        // Ids are ULIDs, so they sort in the order the files were uploaded.
+        if let Some(after) = after {
+            query.insert("id", doc! { "$gt": after });
+        }
         get_collection()
-            .find_one(
+            .find(query)
+            .sort(doc! { "id": 1 })
+            .limit(limit)
+            .await
+            .map_err(|_| Error::DatabaseError)?
+            .try_collect()
+            .await
+            .map_err(|_| Error::DatabaseError)
+    }
+
+    /// Soft-deletes files in the store that were uploaded longer than `ttl` ago and never
+    /// attached, returning how many expired.
+    pub async fn expire_unattached(store_id: &str, ttl: Duration) -> Result<u64> {
+        let cutoff = DateTime::from_system_time(SystemTime::now() - ttl);
+        let result = get_collection()
+            .update_many(
                 doc! {
-                    "id": id,
                     "store": store_id,
-                    "attached": true,
+                    "attached": false,
                     "deleted": false,
+                    "uploadedAt": { "$lt": cutoff },
                 },
+                doc! { "$set": { "deleted": true } },
             )
             .await
+            .map_err(|_| Error::DatabaseError)?;
+        Ok(result.modified_count)
+    }
+
+    pub async fn find(id: &str, store_id: &String) -> Result<File> {
+        get_collection()
+            .find_one(doc! {
+                "id": id,
+                "store": store_id,
+                "attached": true,
+                "deleted": false,
+            })
+            .await
             .map_err(|_| Error::DatabaseError)?
             .ok_or(Error::NotFound)
     }
 
-    pub async fn fetch(&self, resize: Option<Resize>) -> Result<(Vec<u8>, Option<String>)> {
-        let mut contents = Vec::new();
-        if *USE_S3 {
-            let bucket = get_s3_bucket(&self.store)?;
-            let response = bucket
-                .get_object(format!("/{}", self.id))
-                .await
-                .map_err(|_| Error::StorageError)?;
-            if response.status_code() != 200 {
-                return Err(Error::StorageError);
-            }
-            contents = response.bytes().to_vec();
-        } else {
-            let path: PathBuf = format!("{}/{}", *LOCAL_STORAGE_PATH, self.id)
-                .parse()
-                .map_err(|_| Error::StorageError)?;
-
-            let mut f = async_std::fs::File::open(path)
-                .await
-                .map_err(|_| Error::StorageError)?;
-            f.read_to_end(&mut contents)
-                .await
-                .map_err(|_| Error::StorageError)?;
+    pub async fn stream(&self, range: Option<(u64, u64)>) -> Result<ByteStream> {
+        get_backend(&self.store)?.stream(self.key(), range).await
+    }
+
+    fn variant_prefix(&self) -> String {
+        format!("variants/{}/", self.id)
+    }
+
+    pub async fn fetch(&self, resize: &Resize) -> Result<(Vec<u8>, Option<String>)> {
+        let backend = get_backend(&self.store)?;
+        let transform = match self.metadata {
+            FileMetadata::Image { width, height } => width
+                .try_into()
+                .ok()
+                .zip(height.try_into().ok())
+                .and_then(|source| Transform::plan(resize, source)),
+            _ => None,
+        };
+        let Some(transform) = transform else {
+            return Ok((backend.get(self.key()).await?, None));
+        };
+        let content_type = transform.format.content_type().to_string();
+        let key = format!("{}{}", self.variant_prefix(), transform.key());
+        if let Ok(bytes) = backend.get(&key).await {
+            return Ok((bytes, Some(content_type)));
         }
-        let contents_arc = Arc::new(contents);
-        if let Some(parameters) = resize {
-            if let FileMetadata::Image { width, height } = self.metadata {
-                let shortest_length = min(width, height);
-                let (target_width, target_height) = match (
-                    parameters.size,
-                    parameters.max_side,
-                    parameters.width,
-                    parameters.height,
-                ) {
-                    (Some(size), _, _, _) => {
-                        let smallest_size = min(size, shortest_length);
-                        (smallest_size, smallest_size)
-                    }
-                    (_, Some(size), _, _) => {
-                        if shortest_length == width {
-                            let h = min(height, size);
-                            ((width as f32 * (h as f32 / height as f32)) as isize, h)
-                        } else {
-                            let w = min(width, size);
-                            (w, (height as f32 * (w as f32 / width as f32)) as isize)
-                        }
-                    }
-                    (_, _, Some(w), Some(h)) => (min(width, w), min(height, h)),
-                    (_, _, Some(w), _) => {
-                        let w = min(width, w);
-                        (w, (w as f32 * (height as f32 / width as f32)) as isize)
-                    }
-                    (_, _, _, Some(h)) => {
-                        let h = min(height, h);
-                        ((h as f32 * (width as f32 / height as f32)) as isize, h)
-                    }
-                    _ => return Ok((contents_arc.to_vec(), None)),
-                };
-                let contents_arc_moved = contents_arc.clone();
-                if let Ok(bytes) = try_resize(
-                    &contents_arc_moved,
-                    target_width as u32,
-                    target_height as u32,
-                )
-                .await
+        let limits = Store::get(&self.store)?.image_limits;
+        let contents = backend.get(self.key()).await?;
+        let (result, contents) = transform::spawn(move || {
+            let result = transform.apply(&contents, &limits);
+            (result, contents)
+        })
+        .await?;
+        match result {
+            Ok(bytes) => {
+                let cached = backend.list(&self.variant_prefix()).await;
+                if cached.is_ok_and(|variants| variants.len() < MAX_VARIANTS)
+                    && backend.put(&key, &bytes).await.is_err()
                 {
-                    return Ok((bytes, Some("image/webp".to_string())));
+                    warn!("Failed to cache variant {} of file {}", key, self.id);
                 }
+                Ok((bytes, Some(content_type)))
             }
+            Err(Error::ImageTooLarge) => Err(Error::ImageTooLarge),
+            Err(_) => Ok((contents, None)),
         }
-        Ok((contents_arc.to_vec(), None))
     }
 }
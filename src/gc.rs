@@ -0,0 +1,197 @@
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use async_std::stream::StreamExt;
+use async_std::task;
+use log::{info, warn};
+use mongodb::bson::doc;
+
+use crate::blobs::Blob;
+use crate::environment::GC_INTERVAL;
+use crate::files::{get_collection, File};
+use crate::presigned::PresignedObject;
+use crate::storage::{get_backend, StorageBackend};
+use crate::stores;
+use crate::uploads::Upload;
+use crate::usage::Usage;
+
+/// Number of files collected before their objects are deleted together.
+const BATCH_SIZE: usize = 500;
+const MAX_ATTEMPTS: u32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+/// Age after which resumable and presigned uploads that never completed are abandoned.
+const UPLOAD_EXPIRY: Duration = Duration::from_secs(86400);
+
+/// Runs the collector forever, waiting `GC_INTERVAL` after each run finishes so that runs
+/// never overlap.
+pub fn spawn() {
+    task::spawn(async {
+        loop {
+            run().await;
+            task::sleep(*GC_INTERVAL).await;
+        }
+    });
+}
+
+pub async fn run() {
+    expire_unattached().await;
+    match Upload::expire(UPLOAD_EXPIRY).await {
+        Ok(0) => {}
+        Ok(count) => info!("Expired {} incomplete uploads", count),
+        Err(_) => warn!("Failed to expire incomplete uploads"),
+    }
+    match PresignedObject::expire(UPLOAD_EXPIRY).await {
+        Ok(0) => {}
+        Ok(count) => info!("Expired {} unfinalized presigned uploads", count),
+        Err(_) => warn!("Failed to expire presigned uploads"),
+    }
+    abort_stale_uploads().await;
+    collect_blobs().await;
+    let mut cursor = match get_collection()
+        .find(doc! { "deleted": true, "flagged": false })
+        .await
+    {
+        Ok(cursor) => cursor,
+        Err(_) => {
+            warn!("Failed to find files to delete");
+            return;
+        }
+    };
+    let mut batch: HashMap<String, Vec<File>> = HashMap::new();
+    let mut pending = 0;
+    while let Some(result) = cursor.next().await {
+        match result {
+            Ok(file) => {
+                batch.entry(file.store.clone()).or_default().push(file);
+                pending += 1;
+            }
+            Err(_) => warn!("Failed to read file to delete"),
+        }
+        if pending >= BATCH_SIZE {
+            for (store_id, files) in batch.drain() {
+                purge(&store_id, files).await;
+            }
+            pending = 0;
+        }
+    }
+    for (store_id, files) in batch {
+        purge(&store_id, files).await;
+    }
+}
+
+async fn expire_unattached() {
+    for (store_id, store) in stores::get_stores() {
+        let Some(ttl) = store.unattached_ttl else {
+            continue;
+        };
+        match File::expire_unattached(store_id, Duration::from_secs(ttl)).await {
+            Ok(0) => {}
+            Ok(count) => info!("Expired {} unattached files in {}", count, store_id),
+            Err(_) => warn!("Failed to expire unattached files in {}", store_id),
+        }
+    }
+}
+
+/// Aborts multipart uploads left behind in the backends, such as by a crash mid-upload.
+async fn abort_stale_uploads() {
+    for store_id in stores::get_stores().keys() {
+        let Ok(backend) = get_backend(store_id) else {
+            continue;
+        };
+        match backend.abort_stale_uploads(UPLOAD_EXPIRY).await {
+            Ok(0) => {}
+            Ok(count) => info!("Aborted {} stale multipart uploads in {}", count, store_id),
+            Err(_) => warn!("Failed to abort stale multipart uploads in {}", store_id),
+        }
+    }
+}
+
+/// Deletes blobs whose files are all gone. This runs before files are purged, so blobs those
+/// files release wait for the next run.
+async fn collect_blobs() {
+    match Blob::collect().await {
+        Ok(0) => {}
+        Ok(count) => info!("Deleted {} unreferenced blobs", count),
+        Err(_) => warn!("Failed to delete unreferenced blobs"),
+    }
+}
+
+/// Deletes the keys, retrying failures with exponential backoff, and returns the keys that
+/// still could not be deleted.
+async fn delete_with_retries(backend: &dyn StorageBackend, keys: Vec<String>) -> Vec<String> {
+    let mut remaining = keys;
+    let mut backoff = INITIAL_BACKOFF;
+    for attempt in 1..=MAX_ATTEMPTS {
+        remaining = backend.delete_many(&remaining).await.unwrap_or(remaining);
+        if remaining.is_empty() || attempt == MAX_ATTEMPTS {
+            break;
+        }
+        task::sleep(backoff).await;
+        backoff *= 2;
+    }
+    remaining
+}
+
+/// Deletes the objects of the files in a store, then the records of the files whose objects
+/// are all gone, and finally releases their blobs.
+async fn purge(store_id: &str, files: Vec<File>) {
+    let Ok(backend) = get_backend(store_id) else {
+        warn!(
+            "Skipping {} files in unknown store {}",
+            files.len(),
+            store_id
+        );
+        return;
+    };
+    let mut keys = Vec::new();
+    let mut candidates = Vec::new();
+    for file in files {
+        match file.storage_keys().await {
+            Ok(file_keys) => {
+                keys.extend(file_keys.iter().cloned());
+                candidates.push((file, file_keys));
+            }
+            Err(_) => warn!("Failed to list objects of file {}", file.id),
+        }
+    }
+    let failed = delete_with_retries(backend, keys)
+        .await
+        .into_iter()
+        .collect::<HashSet<_>>();
+    let mut deleted = Vec::new();
+    for (file, file_keys) in candidates {
+        if file_keys.iter().any(|key| failed.contains(key)) {
+            warn!("Failed to delete file {} from storage", file.id);
+        } else {
+            deleted.push(file);
+        }
+    }
+    if deleted.is_empty() {
+        return;
+    }
+    let ids = deleted.iter().map(|file| &file.id).collect::<Vec<_>>();
+    if get_collection()
+        .delete_many(doc! { "id": { "$in": &ids }, "store": store_id })
+        .await
+        .is_err()
+    {
+        warn!("Failed to delete records of {} files", deleted.len());
+        return;
+    }
+    for file in deleted {
+        // Releasing after the record is gone means a failure leaks the blob rather than
+        // dropping a reference twice.
+        if let Some(blob) = &file.blob {
+            if Blob::release(store_id, blob).await.is_err() {
+                warn!("Failed to release blob {} of file {}", blob, file.id);
+            }
+        }
+        if Usage::release(store_id, file.uploader.as_deref(), file.size as u64)
+            .await
+            .is_err()
+        {
+            warn!("Failed to release usage of file {}", file.id);
+        }
+        info!("Deleted file {}", file.id);
+    }
+}
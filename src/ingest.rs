@@ -0,0 +1,300 @@
+use std::convert::TryInto;
+use std::path::Path;
+
+use async_std::io::{ReadExt, WriteExt};
+use content_inspector::inspect;
+use futures::StreamExt;
+use mongodb::bson::DateTime;
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
+
+use crate::blobs::Blob;
+use crate::errors::{Error, Result};
+use crate::files::{get_collection, File, FileMetadata};
+use crate::storage::get_backend;
+use crate::stores::{ContentType, Store};
+use crate::usage::Usage;
+use crate::utilities::determine_video_size;
+
+const SNIFF_LENGTH: usize = 8192;
+const IMAGE_HEADER_LENGTH: u64 = 65536;
+const CHUNK_SIZE: usize = 65536;
+
+/// What is learned about uploaded contents while they are written.
+pub struct Fingerprint {
+    pub size: usize,
+    pub head: Vec<u8>,
+    pub hash: String,
+}
+
+#[derive(Default)]
+pub struct Fingerprinter {
+    size: usize,
+    head: Vec<u8>,
+    hasher: Sha256,
+}
+
+impl Fingerprinter {
+    pub fn update(&mut self, data: &[u8]) {
+        self.size += data.len();
+        if self.head.len() < SNIFF_LENGTH {
+            let remaining = SNIFF_LENGTH - self.head.len();
+            self.head
+                .extend_from_slice(&data[..remaining.min(data.len())]);
+        }
+        self.hasher.update(data);
+    }
+
+    pub fn finish(self) -> Fingerprint {
+        Fingerprint {
+            size: self.size,
+            head: self.head,
+            hash: format!("{:x}", self.hasher.finalize()),
+        }
+    }
+
+    /// Fingerprints a file that is already on disk.
+    pub async fn read(path: &Path) -> Result<Fingerprint> {
+        let mut file = async_std::fs::File::open(path)
+            .await
+            .map_err(|_| Error::ProcessingError)?;
+        let mut fingerprinter = Fingerprinter::default();
+        let mut buffer = vec![0; CHUNK_SIZE];
+        loop {
+            let read = file
+                .read(&mut buffer)
+                .await
+                .map_err(|_| Error::ProcessingError)?;
+            if read == 0 {
+                break;
+            }
+            fingerprinter.update(&buffer[..read]);
+        }
+        Ok(fingerprinter.finish())
+    }
+}
+
+/// Writes an upload to a temporary file, enforcing the maximum size of the store.
+pub struct Spool {
+    file: NamedTempFile,
+    writer: async_std::fs::File,
+    max_size: usize,
+    fingerprinter: Fingerprinter,
+}
+
+impl Spool {
+    pub fn new(max_size: usize) -> Result<Spool> {
+        let file = NamedTempFile::new().map_err(|_| Error::ProcessingError)?;
+        let writer = async_std::fs::File::from(file.reopen().map_err(|_| Error::ProcessingError)?);
+        Ok(Spool {
+            file,
+            writer,
+            max_size,
+            fingerprinter: Fingerprinter::default(),
+        })
+    }
+
+    pub async fn write(&mut self, data: &[u8]) -> Result<()> {
+        if self.fingerprinter.size + data.len() > self.max_size {
+            return Err(Error::FileTooLarge {
+                max_size: self.max_size,
+            });
+        }
+        self.fingerprinter.update(data);
+        self.writer
+            .write_all(data)
+            .await
+            .map_err(|_| Error::ProcessingError)
+    }
+
+    pub async fn finish(mut self) -> Result<(NamedTempFile, Fingerprint)> {
+        self.writer
+            .flush()
+            .await
+            .map_err(|_| Error::ProcessingError)?;
+        Ok((self.file, self.fingerprinter.finish()))
+    }
+}
+
+/// Works out the metadata of the contents from their head, the size of the image if they are
+/// one, and a path or URL ffprobe can read them from.
+async fn detect_metadata(
+    store: &Store,
+    content_type: &str,
+    head: &[u8],
+    image_size: Option<imagesize::ImageSize>,
+    media: &Path,
+) -> Result<FileMetadata> {
+    Ok(match content_type {
+        "image/jpeg" | "image/png" | "image/gif" | "image/webp" => {
+            if let Some(imagesize::ImageSize { width, height }) = image_size {
+                store.image_limits.check(width as u64, height as u64)?;
+                FileMetadata::Image {
+                    width: width.try_into().map_err(|_| Error::ProcessingError)?,
+                    height: height.try_into().map_err(|_| Error::ProcessingError)?,
+                }
+            } else {
+                FileMetadata::File
+            }
+        }
+        "video/mp4" | "video/webm" | "video/quicktime" => {
+            if let Ok((width, height)) = determine_video_size(media).await {
+                FileMetadata::Video { width, height }
+            } else {
+                FileMetadata::File
+            }
+        }
+        "audio/mpeg" => FileMetadata::Audio,
+        _ => {
+            if inspect(head).is_text() {
+                FileMetadata::Text
+            } else {
+                FileMetadata::File
+            }
+        }
+    })
+}
+
+fn check_content_type(store: &Store, metadata: &FileMetadata) -> Result<()> {
+    if let Some(content_type) = &store.restrict_content_type {
+        if !matches!(
+            (content_type, metadata),
+            (ContentType::Image, FileMetadata::Image { .. })
+                | (ContentType::Video, FileMetadata::Video { .. })
+                | (ContentType::Audio, FileMetadata::Audio)
+        ) {
+            return Err(Error::FileTypeNotAllowed);
+        }
+    }
+    Ok(())
+}
+
+/// Validates uploaded contents against the store, stores them and records the file.
+pub async fn ingest(
+    store_id: &str,
+    id: String,
+    filename: String,
+    path: &Path,
+    fingerprint: Fingerprint,
+    uploader: Option<String>,
+) -> Result<File> {
+    let store = Store::get(&store_id.to_string())?;
+    if fingerprint.size > store.max_size {
+        return Err(Error::FileTooLarge {
+            max_size: store.max_size,
+        });
+    }
+    let content_type = tree_magic_mini::from_u8(&fingerprint.head);
+    let image_size = imagesize::size(path).ok();
+    let metadata =
+        detect_metadata(store, content_type, &fingerprint.head, image_size, path).await?;
+    check_content_type(store, &metadata)?;
+    let size = fingerprint.size as u64;
+    Usage::reserve(store, store_id, uploader.as_deref(), size).await?;
+    let blob = match Blob::acquire(store_id, &fingerprint.hash, path).await {
+        Ok(blob) => blob,
+        Err(error) => {
+            Usage::release(store_id, uploader.as_deref(), size)
+                .await
+                .ok();
+            return Err(error);
+        }
+    };
+    let file = File {
+        id,
+        store: store_id.to_string(),
+        filename,
+        metadata,
+        content_type: content_type.to_string(),
+        size: fingerprint.size as isize,
+        deleted: false,
+        flagged: false,
+        attached: false,
+        object_id: None,
+        hash: Some(fingerprint.hash),
+        uploaded_at: Some(DateTime::now()),
+        blob: Some(blob.clone()),
+        uploader,
+    };
+    if get_collection().insert_one(&file).await.is_err() {
+        Blob::release(store_id, &blob).await.ok();
+        Usage::release(store_id, file.uploader.as_deref(), size)
+            .await
+            .ok();
+        return Err(Error::DatabaseError);
+    }
+    Ok(file)
+}
+
+/// Validates an object uploaded straight to storage under the key `id` and records the file,
+/// deleting the object if the store rejects it.
+pub async fn ingest_object(
+    store_id: &str,
+    id: String,
+    filename: String,
+    uploader: Option<String>,
+) -> Result<File> {
+    let store = Store::get(&store_id.to_string())?;
+    let backend = get_backend(store_id)?;
+    let object = backend.head(&id).await?.ok_or(Error::NotFound)?;
+    let result = async {
+        if object.size > store.max_size as u64 {
+            return Err(Error::FileTooLarge {
+                max_size: store.max_size,
+            });
+        }
+        // Image headers, such as large EXIF blocks, can run past what is needed for sniffing.
+        let mut head = Vec::new();
+        if object.size > 0 {
+            let mut stream = backend
+                .stream(&id, Some((0, object.size.min(IMAGE_HEADER_LENGTH) - 1)))
+                .await?;
+            while let Some(chunk) = stream.next().await {
+                head.extend_from_slice(&chunk?);
+            }
+        }
+        let sniff = &head[..head.len().min(SNIFF_LENGTH)];
+        let content_type = tree_magic_mini::from_u8(sniff);
+        let image_size = imagesize::blob_size(&head).ok();
+        let url = match content_type {
+            "video/mp4" | "video/webm" | "video/quicktime" => backend.presign_download(&id).await?,
+            _ => String::new(),
+        };
+        let metadata =
+            detect_metadata(store, content_type, sniff, image_size, Path::new(&url)).await?;
+        check_content_type(store, &metadata)?;
+        Usage::reserve(store, store_id, uploader.as_deref(), object.size).await?;
+        Ok((content_type, metadata))
+    }
+    .await;
+    let (content_type, metadata) = match result {
+        Ok(result) => result,
+        Err(error) => {
+            backend.delete(&id).await.ok();
+            return Err(error);
+        }
+    };
+    let file = File {
+        id,
+        store: store_id.to_string(),
+        filename,
+        metadata,
+        content_type: content_type.to_string(),
+        size: object.size as isize,
+        deleted: false,
+        flagged: false,
+        attached: false,
+        object_id: None,
+        hash: None,
+        uploaded_at: Some(DateTime::now()),
+        blob: None,
+        uploader,
+    };
+    if get_collection().insert_one(&file).await.is_err() {
+        Usage::release(store_id, file.uploader.as_deref(), object.size)
+            .await
+            .ok();
+        return Err(Error::DatabaseError);
+    }
+    Ok(file)
+}
@@ -1,26 +1,37 @@
+pub mod authentication;
+pub mod blobs;
+pub mod conditional;
 pub mod constants;
 pub mod database;
 pub mod environment;
 pub mod errors;
 pub mod files;
+pub mod gc;
+pub mod ingest;
 pub mod metadata;
+pub mod presigned;
+pub mod ranges;
+pub mod ratelimit;
+pub mod reconcile;
 pub mod routes;
 pub mod scraper;
+pub mod signing;
+pub mod storage;
 pub mod stores;
+pub mod transform;
+pub mod uploads;
+pub mod usage;
 pub mod utilities;
 
-use std::{env, time::Duration};
+use std::env;
 
 use actix_cors::Cors;
 use actix_files::Files;
-use actix_web::{middleware::Logger, web, App, HttpServer};
-use async_std::stream::StreamExt;
-use async_std::{fs::create_dir_all, task};
+use actix_web::middleware::{from_fn, Logger};
+use actix_web::{http::Method, web, App, HttpServer};
 use log::info;
-use mongodb::bson::doc;
 
-use crate::environment::{HOST, LOCAL_STORAGE_PATH, USE_S3};
-use crate::files::get_collection;
+use crate::environment::HOST;
 
 #[async_std::main]
 async fn main() -> std::io::Result<()> {
@@ -36,58 +47,105 @@ async fn main() -> std::io::Result<()> {
     info!("Nextflow CDN version {}", constants::VERSION);
 
     stores::load_stores().expect("Failed to load stores");
+    authentication::load_keys().expect("Failed to load JWT keys");
 
     info!("Connecting to database...");
     database::connect().await;
 
-    if !*USE_S3 {
-        info!("Using local storage, the directory will be created if it does not exist!");
-        create_dir_all(LOCAL_STORAGE_PATH.to_string())
-            .await
-            .expect("Failed to create local storage directory");
-    } else {
-        info!("Using S3 storage, make sure all configured stores have buckets!");
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if args.first().is_some_and(|command| command == "reconcile") {
+        reconcile::run(args.iter().any(|arg| arg == "--dry-run")).await;
+        return Ok(());
     }
 
     info!("Starting background tasks...");
-    task::spawn(async {
-        loop {
-            task::spawn(async {
-                let collection = get_collection();
-                let mut cursor = collection
-                    .find(
-                        doc! {
-                            "deleted": true,
-                            "flagged": false
-                        },
-                    )
-                    .await
-                    .expect("Failed to find files to delete");
-                while let Some(result) = cursor.next().await {
-                    if let Ok(file) = result {
-                        file.delete().await.expect("Failed to delete file");
-                    }
-                    task::sleep(Duration::from_millis(50)).await;
-                }
-            });
-            task::sleep(Duration::from_secs(900)).await;
-        }
-    });
+    gc::spawn();
+    reconcile::spawn();
 
     info!("Starting server on {}...", *HOST);
     HttpServer::new(|| {
         App::new()
+            // Runs inside authentication, so that callers are limited by their identity.
+            .wrap(from_fn(ratelimit::limit))
+            // Registered before CORS so that errors from authentication still carry CORS headers.
+            .wrap(from_fn(authentication::authenticate))
             .wrap(
                 Cors::default()
                     .allowed_origin_fn(|_, _| true)
                     .allow_any_method()
                     .allow_any_header()
+                    .expose_any_header()
                     .supports_credentials(),
             )
             .wrap(Logger::default())
             .service(Files::new("/assets", "assets"))
             .route("/", web::get().to(routes::service::handle))
             .route("/stores/{store}", web::post().to(routes::upload::handle))
+            .route(
+                "/stores/{store}/import",
+                web::post().to(routes::import::handle),
+            )
+            .route(
+                "/stores/{store}/presign",
+                web::post().to(routes::presign::handle),
+            )
+            .route(
+                "/stores/{store}/presign/{id}",
+                web::post().to(routes::presign::finalize),
+            )
+            .route(
+                "/stores/{store}/tus",
+                web::method(Method::OPTIONS).to(routes::tus::options),
+            )
+            .route("/stores/{store}/tus", web::post().to(routes::tus::create))
+            .route(
+                "/stores/{store}/tus/{id}",
+                web::head().to(routes::tus::head),
+            )
+            .route(
+                "/stores/{store}/tus/{id}",
+                web::patch().to(routes::tus::patch),
+            )
+            .route(
+                "/stores/{store}/tus/{id}",
+                web::delete().to(routes::tus::terminate),
+            )
+            .route(
+                "/stores/{store}/usage",
+                web::get().to(routes::usage::handle),
+            )
+            .route(
+                "/stores/{store}/usage/{uploader}",
+                web::get().to(routes::usage::handle_uploader),
+            )
+            .route(
+                "/stores/{store}/attach",
+                web::post().to(routes::attach::handle_bulk),
+            )
+            .route(
+                "/stores/{store}/detach",
+                web::post().to(routes::detach::handle_bulk),
+            )
+            .route(
+                "/stores/{store}/delete",
+                web::post().to(routes::delete::handle_bulk),
+            )
+            .route(
+                "/stores/{store}/files/{id}/attach",
+                web::post().to(routes::attach::handle),
+            )
+            .route(
+                "/stores/{store}/files/{id}/detach",
+                web::post().to(routes::detach::handle),
+            )
+            .route(
+                "/stores/{store}/files/{id}/sign",
+                web::post().to(routes::sign::handle),
+            )
+            .route(
+                "/stores/{store}/files/{id}",
+                web::delete().to(routes::delete::handle),
+            )
             .route(
                 "/stores/{store}/download/{filename:.*}",
                 web::get().to(routes::download::handle),
@@ -96,6 +154,14 @@ async fn main() -> std::io::Result<()> {
                 "/stores/{store}/files/{filename:.*}",
                 web::get().to(routes::serve::handle),
             )
+            .route(
+                "/uploaders/{uploader}/files",
+                web::get().to(routes::uploader::list),
+            )
+            .route(
+                "/uploaders/{uploader}/files",
+                web::delete().to(routes::uploader::delete),
+            )
             .route("/embed", web::get().to(routes::embed::handle))
             .route("/proxy", web::get().to(routes::proxy::handle))
     })
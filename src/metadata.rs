@@ -10,7 +10,7 @@ use youtubei_rs::{query::player, utils::default_client_config};
 use crate::{
     errors::{Error, Result},
     scraper::get_twitch_channel,
-    utilities::{fetch, get_media_size, Image, ImageSize, Special, Video},
+    utilities::{fetch, get_media_size, FetchOptions, Image, ImageSize, Special, Video},
 };
 
 lazy_static! {
@@ -161,7 +161,7 @@ impl Metadata {
             if image.width != 0 && image.height != 0 {
                 return Ok(());
             }
-            let (resp, mime) = fetch(&image.url).await?;
+            let (resp, mime) = fetch(&image.url, &FetchOptions::default()).await?;
             let (width, height) = get_media_size(resp, mime).await?;
             image.width = width;
             image.height = height;
@@ -219,7 +219,7 @@ impl Metadata {
                 Special::Youtube { .. } => self.color = Some("#FF424F".to_string()),
                 Special::Twitch { .. } => self.color = Some("#7B68EE".to_string()),
                 Special::Spotify { .. } => self.color = Some("#1ABC9C".to_string()),
-                Special::Soundcloud { .. } => self.color = Some("#FF7F50".to_string()),
+                Special::Soundcloud => self.color = Some("#FF7F50".to_string()),
                 _ => {}
             }
             self.special = Some(special);
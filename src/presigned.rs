@@ -0,0 +1,91 @@
+use std::time::{Duration, SystemTime};
+
+use mongodb::{
+    bson::{doc, DateTime},
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    authentication::Identity,
+    database::DATABASE,
+    environment::MONGODB_DATABASE,
+    errors::{Error, Result},
+};
+
+pub fn get_collection() -> Collection<PresignedObject> {
+    DATABASE
+        .get()
+        .expect("Failed to get MongoDB client")
+        .database(&MONGODB_DATABASE)
+        .collection("presigned")
+}
+
+/// An object a caller was allowed to upload straight to storage, awaiting finalization.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PresignedObject {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub store: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uploader: Option<String>,
+    pub created_at: DateTime,
+}
+
+impl PresignedObject {
+    pub async fn create(
+        id: &str,
+        store_id: &str,
+        uploader: Option<String>,
+    ) -> Result<PresignedObject> {
+        let object = PresignedObject {
+            id: id.to_string(),
+            store: store_id.to_string(),
+            uploader,
+            created_at: DateTime::now(),
+        };
+        get_collection()
+            .insert_one(&object)
+            .await
+            .map_err(|_| Error::DatabaseError)?;
+        Ok(object)
+    }
+
+    pub async fn find(id: &str, store_id: &str) -> Result<PresignedObject> {
+        get_collection()
+            .find_one(doc! { "_id": id, "store": store_id })
+            .await
+            .map_err(|_| Error::DatabaseError)?
+            .ok_or(Error::NotFound)
+    }
+
+    /// Fails unless the caller is the one the object was presigned for.
+    pub fn check_owner(&self, identity: Option<&Identity>) -> Result<()> {
+        match &self.uploader {
+            Some(uploader) if identity.map(|identity| &identity.subject) != Some(uploader) => {
+                Err(Error::Forbidden)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub async fn delete(self) -> Result<()> {
+        get_collection()
+            .delete_one(doc! { "_id": &self.id })
+            .await
+            .map_err(|_| Error::DatabaseError)?;
+        Ok(())
+    }
+
+    /// Forgets objects presigned longer than `ttl` ago that were never finalized, returning
+    /// how many were forgotten. Anything uploaded for them is left to reconciliation.
+    pub async fn expire(ttl: Duration) -> Result<u64> {
+        let cutoff = DateTime::from_system_time(SystemTime::now() - ttl);
+        let result = get_collection()
+            .delete_many(doc! { "createdAt": { "$lt": cutoff } })
+            .await
+            .map_err(|_| Error::DatabaseError)?;
+        Ok(result.deleted_count)
+    }
+}
@@ -0,0 +1,177 @@
+use actix_files::HttpRange;
+use actix_web::http::header::{ACCEPT_RANGES, CONTENT_RANGE, RANGE};
+use actix_web::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse, HttpResponseBuilder};
+use bytes::Bytes;
+use futures::{future, stream, Stream, StreamExt, TryStreamExt};
+
+use crate::conditional::is_range_fresh;
+use crate::errors::Result;
+use crate::files::File;
+use crate::storage::ByteStream;
+
+const MAX_RANGES: usize = 16;
+
+/// Returns the ranges requested by the client, `None` if the whole body should be sent and
+/// an empty vector if the ranges cannot be satisfied.
+fn requested_ranges(req: &HttpRequest, file: &File, size: u64) -> Option<Vec<HttpRange>> {
+    let range = req.headers().get(RANGE)?.to_str().ok()?;
+    if !is_range_fresh(req, file) {
+        return None;
+    }
+    match HttpRange::parse(range, size) {
+        Ok(ranges) if ranges.is_empty() || ranges.len() > MAX_RANGES => None,
+        Ok(ranges) => Some(ranges),
+        Err(_) => Some(Vec::new()),
+    }
+}
+
+fn chunk(contents: String) -> ByteStream {
+    Box::pin(stream::once(future::ok(Bytes::from(contents))))
+}
+
+fn body(
+    contents: impl Stream<Item = Result<Bytes>> + 'static,
+) -> impl Stream<Item = std::io::Result<Bytes>> {
+    contents.map_err(|_| std::io::Error::other("Failed to stream file"))
+}
+
+pub async fn respond(
+    req: &HttpRequest,
+    file: &File,
+    mut response: HttpResponseBuilder,
+) -> Result<HttpResponse> {
+    let size = file.size as u64;
+    response.insert_header((ACCEPT_RANGES, "bytes"));
+    match requested_ranges(req, file, size).as_deref() {
+        None => Ok(response
+            .content_type(file.content_type.clone())
+            .no_chunking(size)
+            .streaming(body(file.stream(None).await?))),
+        Some([]) => Ok(response
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .insert_header((CONTENT_RANGE, format!("bytes */{}", size)))
+            .finish()),
+        Some([range]) => {
+            let end = range.start + range.length - 1;
+            Ok(response
+                .status(StatusCode::PARTIAL_CONTENT)
+                .insert_header((
+                    CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", range.start, end, size),
+                ))
+                .content_type(file.content_type.clone())
+                .no_chunking(range.length)
+                .streaming(body(file.stream(Some((range.start, end))).await?)))
+        }
+        Some(ranges) => {
+            let boundary = ulid::Ulid::new().to_string();
+            let mut length = 0;
+            let mut parts = Vec::new();
+            for range in ranges {
+                let end = range.start + range.length - 1;
+                let header = format!(
+                    "--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                    boundary, file.content_type, range.start, end, size
+                );
+                length += header.len() as u64 + range.length + 2;
+                parts.push(chunk(header));
+                parts.push(file.stream(Some((range.start, end))).await?);
+                parts.push(chunk("\r\n".to_string()));
+            }
+            let trailer = format!("--{}--\r\n", boundary);
+            length += trailer.len() as u64;
+            parts.push(chunk(trailer));
+            Ok(response
+                .status(StatusCode::PARTIAL_CONTENT)
+                .content_type(format!("multipart/byteranges; boundary={}", boundary))
+                .no_chunking(length)
+                .streaming(body(stream::iter(parts).flatten())))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::IF_RANGE;
+    use actix_web::test::TestRequest;
+
+    use super::*;
+    use crate::files::FileMetadata;
+
+    fn file() -> File {
+        File {
+            id: "01J00000000000000000000000".to_string(),
+            store: "attachments".to_string(),
+            filename: "file.bin".to_string(),
+            metadata: FileMetadata::File,
+            content_type: "application/octet-stream".to_string(),
+            size: 100,
+            attached: true,
+            deleted: false,
+            flagged: false,
+            object_id: None,
+            hash: Some("abc".to_string()),
+            uploaded_at: None,
+            blob: None,
+            uploader: None,
+        }
+    }
+
+    fn ranges(headers: &[(&str, &str)]) -> Option<Vec<(u64, u64)>> {
+        let mut req = TestRequest::default();
+        for header in headers {
+            req = req.insert_header(*header);
+        }
+        requested_ranges(&req.to_http_request(), &file(), 100).map(|ranges| {
+            ranges
+                .into_iter()
+                .map(|range| (range.start, range.length))
+                .collect()
+        })
+    }
+
+    #[test]
+    fn whole_body_without_range() {
+        assert_eq!(ranges(&[]), None);
+    }
+
+    #[test]
+    fn single_and_multiple_ranges() {
+        assert_eq!(ranges(&[("Range", "bytes=0-9")]), Some(vec![(0, 10)]));
+        assert_eq!(ranges(&[("Range", "bytes=-10")]), Some(vec![(90, 10)]));
+        assert_eq!(
+            ranges(&[("Range", "bytes=0-9,50-59")]),
+            Some(vec![(0, 10), (50, 10)])
+        );
+    }
+
+    #[test]
+    fn unsatisfiable_range() {
+        assert_eq!(ranges(&[("Range", "bytes=200-300")]), Some(Vec::new()));
+    }
+
+    #[test]
+    fn too_many_ranges_send_whole_body() {
+        let range = format!(
+            "bytes={}",
+            (0..=MAX_RANGES)
+                .map(|i| format!("{}-{}", i * 2, i * 2))
+                .collect::<Vec<_>>()
+                .join(",")
+        );
+        assert_eq!(ranges(&[("Range", &range)]), None);
+    }
+
+    #[test]
+    fn stale_if_range_sends_whole_body() {
+        assert_eq!(
+            ranges(&[("Range", "bytes=0-9"), (IF_RANGE.as_str(), "\"abc\"")]),
+            Some(vec![(0, 10)])
+        );
+        assert_eq!(
+            ranges(&[("Range", "bytes=0-9"), (IF_RANGE.as_str(), "\"def\"")]),
+            None
+        );
+    }
+}
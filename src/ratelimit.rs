@@ -0,0 +1,316 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::HttpMessage;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use crate::authentication::{Credential, Identity};
+use crate::environment::{RATE_LIMIT_EMBED, RATE_LIMIT_PROXY, RATE_LIMIT_UPLOAD, TRUST_PROXY};
+use crate::errors::Error;
+use crate::stores::Store;
+
+/// Most callers tracked at once, across all limits.
+const MAX_BUCKETS: usize = 100000;
+
+lazy_static! {
+    static ref BUCKETS: Mutex<Buckets> = Mutex::new(Buckets::new(MAX_BUCKETS));
+}
+
+/// Allows bursts of `requests` requests, refilling at that many every `per` seconds.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct RateLimit {
+    pub requests: u32,
+    pub per: u64,
+}
+
+impl RateLimit {
+    /// Parses a limit written as `requests/seconds`.
+    pub fn parse(value: &str) -> Option<RateLimit> {
+        let (requests, per) = value.split_once('/')?;
+        let limit = RateLimit {
+            requests: requests.trim().parse().ok()?,
+            per: per.trim().parse().ok()?,
+        };
+        (limit.requests > 0 && limit.per > 0).then_some(limit)
+    }
+
+    fn rate(&self) -> f64 {
+        self.requests as f64 / self.per as f64
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// Position of the bucket in `Buckets::used`.
+    used: u64,
+}
+
+/// Buckets of the callers seen most recently, forgetting the least recently used caller once
+/// `capacity` are tracked.
+struct Buckets {
+    buckets: HashMap<String, Bucket>,
+    /// Keys of the buckets, ordered from least to most recently used.
+    used: BTreeMap<u64, String>,
+    next: u64,
+    capacity: usize,
+}
+
+impl Buckets {
+    fn new(capacity: usize) -> Buckets {
+        Buckets {
+            buckets: HashMap::new(),
+            used: BTreeMap::new(),
+            next: 0,
+            capacity,
+        }
+    }
+
+    /// Takes a token from the bucket under `key`, returning how many seconds to wait for one
+    /// if it is empty.
+    fn take(&mut self, key: String, limit: &RateLimit) -> Result<(), u64> {
+        let now = Instant::now();
+        match self.buckets.get(&key) {
+            Some(bucket) => {
+                self.used.remove(&bucket.used);
+            }
+            None if self.buckets.len() >= self.capacity => {
+                // A forgotten caller starts over with a full bucket, which only ever lets
+                // through more than the limit.
+                if let Some((_, evicted)) = self.used.pop_first() {
+                    self.buckets.remove(&evicted);
+                }
+            }
+            None => {}
+        }
+        let used = self.next;
+        self.next += 1;
+        self.used.insert(used, key.clone());
+        let bucket = self.buckets.entry(key).or_insert(Bucket {
+            tokens: limit.requests as f64,
+            updated: now,
+            used,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * limit.rate()).min(limit.requests as f64);
+        bucket.updated = now;
+        bucket.used = used;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / limit.rate()).ceil() as u64)
+        }
+    }
+}
+
+fn take(key: String, limit: &RateLimit) -> Result<(), u64> {
+    match BUCKETS.lock() {
+        Ok(mut buckets) => buckets.take(key, limit),
+        Err(_) => Ok(()),
+    }
+}
+
+/// Groups IPv6 addresses by their /64 prefix, which is usually all a single host is given.
+fn address_group(address: &str) -> String {
+    match address.parse::<IpAddr>() {
+        Ok(IpAddr::V6(address)) => match address.to_ipv4_mapped() {
+            Some(address) => address.to_string(),
+            None => {
+                let prefix = u128::from(address) & !(u128::MAX >> 64);
+                format!("{}/64", Ipv6Addr::from(prefix))
+            }
+        },
+        Ok(address) => address.to_string(),
+        Err(_) => address.to_string(),
+    }
+}
+
+/// Address of the caller. Behind a trusted proxy, this is the last `X-Forwarded-For` entry,
+/// which the proxy appended; earlier entries come from the caller and can be forged.
+fn client_address(req: &ServiceRequest) -> String {
+    if *TRUST_PROXY {
+        let forwarded = req
+            .headers()
+            .get_all("x-forwarded-for")
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|address| !address.is_empty())
+            .last();
+        if let Some(address) = forwarded {
+            return address.to_string();
+        }
+    }
+    req.peer_addr()
+        .map(|address| address.ip().to_string())
+        .unwrap_or_default()
+}
+
+/// Store of the upload a POST to `path` starts, if it starts one. Resuming or finalizing an
+/// upload does not count, so that it is never held up.
+fn upload_store(path: &str) -> Option<&str> {
+    match path.split('/').collect::<Vec<_>>()[..] {
+        ["stores", store_id] | ["stores", store_id, "import" | "presign" | "tus"] => Some(store_id),
+        _ => None,
+    }
+}
+
+/// Works out which limit applies to the request, along with the name of its bucket.
+fn classify(req: &ServiceRequest) -> Option<(String, RateLimit)> {
+    let path = req.path().trim_start_matches('/');
+    match path.split('/').next()? {
+        "embed" => Some(("embed".to_string(), (*RATE_LIMIT_EMBED)?)),
+        "proxy" => Some(("proxy".to_string(), (*RATE_LIMIT_PROXY)?)),
+        "stores" if req.method() == Method::POST => {
+            let store_id = upload_store(path)?;
+            // Unknown stores are rejected anyway, and must not each get their own bucket.
+            let limit = Store::get(&store_id.to_string())
+                .ok()?
+                .rate_limit
+                .or(*RATE_LIMIT_UPLOAD)?;
+            Some((format!("upload/{}", store_id), limit))
+        }
+        _ => None,
+    }
+}
+
+/// Middleware limiting how often each caller may upload, embed and proxy, identifying callers
+/// by their credential or, if anonymous, their address. Trusted services are not limited.
+pub async fn limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    if let Some((name, limit)) = classify(&req) {
+        let caller = match req.extensions().get::<Identity>() {
+            Some(Identity {
+                credential: Credential::Service,
+                ..
+            }) => None,
+            Some(Identity {
+                subject,
+                credential: Credential::ApiKey,
+            }) => Some(format!("key:{}", subject)),
+            Some(Identity { subject, .. }) => Some(format!("user:{}", subject)),
+            None => Some(format!("ip:{}", address_group(&client_address(&req)))),
+        };
+        if let Some(caller) = caller {
+            if let Err(retry_after) = take(format!("{}:{}", name, caller), &limit) {
+                return Ok(req
+                    .error_response(Error::RateLimited { retry_after })
+                    .map_into_right_body());
+            }
+        }
+    }
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_limits() {
+        let limit = RateLimit::parse("30/60").unwrap();
+        assert_eq!((limit.requests, limit.per), (30, 60));
+        assert!(RateLimit::parse("off").is_none());
+        assert!(RateLimit::parse("0/60").is_none());
+        assert!(RateLimit::parse("30/0").is_none());
+    }
+
+    #[test]
+    fn empties_bucket_after_burst() {
+        let mut buckets = Buckets::new(10);
+        let limit = RateLimit {
+            requests: 2,
+            per: 10,
+        };
+        assert!(buckets.take("burst".to_string(), &limit).is_ok());
+        assert!(buckets.take("burst".to_string(), &limit).is_ok());
+        assert_eq!(buckets.take("burst".to_string(), &limit), Err(5));
+    }
+
+    #[test]
+    fn keeps_callers_apart() {
+        let mut buckets = Buckets::new(10);
+        let limit = RateLimit {
+            requests: 1,
+            per: 60,
+        };
+        assert!(buckets.take("first".to_string(), &limit).is_ok());
+        assert!(buckets.take("first".to_string(), &limit).is_err());
+        assert!(buckets.take("second".to_string(), &limit).is_ok());
+    }
+
+    #[test]
+    fn forgets_least_recently_used_caller() {
+        let mut buckets = Buckets::new(2);
+        let limit = RateLimit {
+            requests: 1,
+            per: 60,
+        };
+        assert!(buckets.take("first".to_string(), &limit).is_ok());
+        assert!(buckets.take("second".to_string(), &limit).is_ok());
+        assert!(buckets.take("first".to_string(), &limit).is_err());
+        // New callers are let in, forgetting the caller seen longest ago.
+        assert!(buckets.take("third".to_string(), &limit).is_ok());
+        assert_eq!(buckets.buckets.len(), 2);
+        assert!(buckets.take("first".to_string(), &limit).is_err());
+        assert!(buckets.take("second".to_string(), &limit).is_ok());
+    }
+
+    #[test]
+    fn groups_addresses() {
+        assert_eq!(address_group("192.0.2.1"), "192.0.2.1");
+        assert_eq!(
+            address_group("2001:db8:1:2:3:4:5:6"),
+            address_group("2001:db8:1:2:ffff::1")
+        );
+        assert_eq!(address_group("2001:db8:1:2:3:4:5:6"), "2001:db8:1:2::/64");
+        assert_ne!(
+            address_group("2001:db8:1:2::1"),
+            address_group("2001:db8:1:3::1")
+        );
+        assert_eq!(address_group("::ffff:192.0.2.1"), "192.0.2.1");
+    }
+
+    #[test]
+    fn takes_address_appended_by_proxy() {
+        std::env::set_var("TRUST_PROXY", "true");
+        let req = actix_web::test::TestRequest::default()
+            .insert_header(("X-Forwarded-For", "198.51.100.1, 203.0.113.1"))
+            .insert_header(("X-Forwarded-For", "192.0.2.1"))
+            .peer_addr("127.0.0.1:8080".parse().unwrap())
+            .to_srv_request();
+        assert_eq!(client_address(&req), "192.0.2.1");
+    }
+
+    #[test]
+    fn limits_only_starting_uploads() {
+        assert_eq!(upload_store("stores/avatars"), Some("avatars"));
+        assert_eq!(upload_store("stores/avatars/import"), Some("avatars"));
+        assert_eq!(upload_store("stores/avatars/presign"), Some("avatars"));
+        assert_eq!(upload_store("stores/avatars/tus"), Some("avatars"));
+        assert_eq!(
+            upload_store("stores/avatars/presign/01ARZ3NDEKTSV4RRFFQ69G5FAV"),
+            None
+        );
+        assert_eq!(
+            upload_store("stores/avatars/tus/01ARZ3NDEKTSV4RRFFQ69G5FAV"),
+            None
+        );
+        assert_eq!(
+            upload_store("stores/avatars/files/01ARZ3NDEKTSV4RRFFQ69G5FAV/sign"),
+            None
+        );
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime};
+
+use async_std::stream::StreamExt;
+use async_std::task;
+use log::{info, warn};
+use mongodb::bson::{doc, Bson, DateTime};
+use mongodb::Collection;
+use ulid::Ulid;
+
+use crate::blobs;
+use crate::environment::{RECONCILE_DRY_RUN, RECONCILE_INTERVAL};
+use crate::errors::{Error, Result};
+use crate::files::{get_collection, File};
+use crate::storage::get_backend;
+use crate::stores;
+
+/// Files uploaded more recently than this are skipped, as their object may still be in flight.
+const GRACE_PERIOD: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Keys of objects that belong to no file.
+    pub orphaned_objects: Vec<String>,
+    /// Ids of files whose object is missing.
+    pub missing_objects: Vec<String>,
+}
+
+/// Runs reconciliation on the interval given by `RECONCILE_INTERVAL`, if any.
+pub fn spawn() {
+    let Some(interval) = *RECONCILE_INTERVAL else {
+        return;
+    };
+    task::spawn(async move {
+        loop {
+            task::sleep(interval).await;
+            run(*RECONCILE_DRY_RUN).await;
+        }
+    });
+}
+
+/// Reconciles every store, only reporting mismatches if `dry_run` is set.
+pub async fn run(dry_run: bool) {
+    for store_id in stores::get_stores().keys() {
+        match reconcile(store_id, dry_run).await {
+            Ok(report) => info!(
+                "Reconciled {}: {} orphaned objects, {} missing objects",
+                store_id,
+                report.orphaned_objects.len(),
+                report.missing_objects.len()
+            ),
+            Err(_) => warn!("Failed to reconcile {}", store_id),
+        }
+    }
+}
+
+/// Id of the file an object key belongs to, for the original and its variants.
+fn file_id(key: &str) -> Option<&str> {
+    match key.strip_prefix("variants/") {
+        Some(variant) => variant.split('/').next(),
+        None if !key.contains('/') => Some(key),
+        // Other prefixes hold data that is not tied to a single file.
+        None => None,
+    }
+}
+
+/// Whether the id was generated within the grace period, as for objects uploaded straight to
+/// storage that are yet to be finalized.
+fn is_recent(id: &str) -> bool {
+    Ulid::from_string(id).is_ok_and(|ulid| {
+        ulid.datetime()
+            .elapsed()
+            .map_or(true, |age| age < GRACE_PERIOD)
+    })
+}
+
+/// Picks the values of `field` in `collection` that are among `values`.
+async fn existing<T: Send + Sync>(
+    collection: Collection<T>,
+    field: &str,
+    values: Vec<&str>,
+) -> Result<HashSet<String>> {
+    if values.is_empty() {
+        return Ok(HashSet::new());
+    }
+    Ok(collection
+        .distinct(field, doc! { field: { "$in": values } })
+        .await
+        .map_err(|_| Error::DatabaseError)?
+        .into_iter()
+        .filter_map(|value| match value {
+            Bson::String(value) => Some(value),
+            _ => None,
+        })
+        .collect())
+}
+
+pub async fn reconcile(store_id: &str, dry_run: bool) -> Result<Report> {
+    let backend = get_backend(store_id)?;
+    // List storage before the database, since records are always written before objects.
+    let keys = backend
+        .list("")
+        .await?
+        .into_iter()
+        .map(|object| object.key)
+        .collect::<Vec<_>>();
+    let mut files = Vec::new();
+    let mut cursor = get_collection()
+        .find(doc! { "store": store_id })
+        .await
+        .map_err(|_| Error::DatabaseError)?;
+    while let Some(file) = cursor.next().await {
+        files.push(file.map_err(|_| Error::DatabaseError)?);
+    }
+
+    // Backends may be shared between stores, so look up unknown ids across all of them.
+    let known = files
+        .iter()
+        .map(|file| file.id.as_str())
+        .collect::<HashSet<_>>();
+    let unknown = keys
+        .iter()
+        .filter_map(|key| file_id(key))
+        .filter(|id| !known.contains(id))
+        .collect::<HashSet<_>>();
+    let elsewhere = existing(get_collection(), "id", unknown.into_iter().collect()).await?;
+    let blobs = keys
+        .iter()
+        .filter(|key| key.starts_with("blobs/"))
+        .map(String::as_str)
+        .collect();
+    let blobs = existing(blobs::get_collection(), "key", blobs).await?;
+    let orphaned_objects = keys
+        .iter()
+        .filter(|key| match file_id(key) {
+            Some(id) => !known.contains(id) && !elsewhere.contains(id) && !is_recent(id),
+            None => key.starts_with("blobs/") && !blobs.contains(*key),
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let stored = keys.iter().map(String::as_str).collect::<HashSet<_>>();
+    let cutoff = DateTime::from_system_time(SystemTime::now() - GRACE_PERIOD);
+    let missing_objects = files
+        .iter()
+        .filter(|file| !file.deleted && file.uploaded_at.is_none_or(|at| at < cutoff))
+        .filter(|file| !stored.contains(file.key()))
+        .map(|file| file.id.clone())
+        .collect::<Vec<_>>();
+
+    for key in &orphaned_objects {
+        info!("Object {} in {} belongs to no file", key, store_id);
+    }
+    for id in &missing_objects {
+        info!("File {} in {} has no object", id, store_id);
+    }
+    if !dry_run {
+        if !orphaned_objects.is_empty() {
+            let failed = backend.delete_many(&orphaned_objects).await?;
+            for key in failed {
+                warn!("Failed to delete orphaned object {}", key);
+            }
+        }
+        if !missing_objects.is_empty() {
+            File::mark_deleted(store_id, &missing_objects).await?;
+        }
+    }
+    Ok(Report {
+        orphaned_objects,
+        missing_objects,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_files_of_keys() {
+        let id = "01J00000000000000000000000";
+        assert_eq!(file_id(id), Some(id));
+        assert_eq!(file_id(&format!("variants/{}/w100-q80.webp", id)), Some(id));
+        assert_eq!(file_id("blobs/avatars/abc"), None);
+    }
+
+    #[test]
+    fn spares_recent_ids() {
+        assert!(is_recent(&Ulid::new().to_string()));
+        let old = Ulid::from_datetime(SystemTime::now() - 2 * GRACE_PERIOD);
+        assert!(!is_recent(&old.to_string()));
+        assert!(!is_recent("not-a-ulid"));
+    }
+}
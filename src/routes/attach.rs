@@ -0,0 +1,51 @@
+use actix_web::{web, HttpResponse, Responder};
+use serde::Deserialize;
+use validator::Validate;
+
+use crate::authentication::Service;
+use crate::errors::{Error, Result};
+use crate::files::File;
+use crate::routes::BulkResponse;
+use crate::stores::Store;
+
+#[derive(Deserialize, Validate)]
+#[serde(rename_all = "camelCase")]
+pub struct AttachRequest {
+    #[validate(length(min = 1, max = 128))]
+    object_id: String,
+}
+
+#[derive(Deserialize, Validate)]
+#[serde(rename_all = "camelCase")]
+pub struct BulkAttachRequest {
+    #[validate(length(min = 1, max = 100))]
+    ids: Vec<String>,
+    #[validate(length(min = 1, max = 128))]
+    object_id: String,
+}
+
+pub async fn handle(
+    _: Service,
+    path: web::Path<(String, String)>,
+    body: web::Json<AttachRequest>,
+) -> Result<impl Responder> {
+    let (store_id, id) = path.into_inner();
+    Store::get(&store_id)?;
+    body.validate().map_err(|_| Error::ValidationFailed)?;
+    if File::attach(&store_id, &[id], &body.object_id).await? == 0 {
+        return Err(Error::NotFound);
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn handle_bulk(
+    _: Service,
+    path: web::Path<String>,
+    body: web::Json<BulkAttachRequest>,
+) -> Result<impl Responder> {
+    let store_id = path.into_inner();
+    Store::get(&store_id)?;
+    body.validate().map_err(|_| Error::ValidationFailed)?;
+    let count = File::attach(&store_id, &body.ids, &body.object_id).await?;
+    Ok(web::Json(BulkResponse { count }))
+}
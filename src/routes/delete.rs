@@ -0,0 +1,29 @@
+use actix_web::{web, HttpResponse, Responder};
+use validator::Validate;
+
+use crate::authentication::Service;
+use crate::errors::{Error, Result};
+use crate::files::File;
+use crate::routes::{BulkRequest, BulkResponse};
+use crate::stores::Store;
+
+pub async fn handle(_: Service, path: web::Path<(String, String)>) -> Result<impl Responder> {
+    let (store_id, id) = path.into_inner();
+    Store::get(&store_id)?;
+    if File::mark_deleted(&store_id, &[id]).await? == 0 {
+        return Err(Error::NotFound);
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn handle_bulk(
+    _: Service,
+    path: web::Path<String>,
+    body: web::Json<BulkRequest>,
+) -> Result<impl Responder> {
+    let store_id = path.into_inner();
+    Store::get(&store_id)?;
+    body.validate().map_err(|_| Error::ValidationFailed)?;
+    let count = File::mark_deleted(&store_id, &body.ids).await?;
+    Ok(web::Json(BulkResponse { count }))
+}
@@ -1,21 +1,31 @@
-use actix_web::{web, HttpResponse, Responder};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse};
 
-use crate::constants::CACHE_CONTROL;
+use crate::conditional;
 use crate::errors::Result;
 use crate::files::File;
+use crate::ranges;
+use crate::signing::Signature;
 use crate::stores::Store;
 
-pub async fn handle(path: web::Path<(String, String)>) -> Result<impl Responder> {
+pub async fn handle(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    signature: web::Query<Signature>,
+) -> Result<HttpResponse> {
     let (store_id, id) = path.into_inner();
-    Store::get(&store_id)?;
+    let cache_control = Store::get(&store_id)?.authorize_read(&store_id, &id, &signature)?;
     let file = File::find(&id, &store_id).await?;
-    let (contents, _) = file.fetch(None).await?;
-    Ok(HttpResponse::Ok()
+    let mut response = HttpResponse::Ok();
+    response
         .insert_header((
             "Content-Disposition",
             format!("attachment; filename=\"{}\"", file.filename),
         ))
-        .insert_header(("Cache-Control", CACHE_CONTROL))
-        .content_type(file.content_type)
-        .body(contents))
+        .insert_header(("Cache-Control", cache_control));
+    conditional::insert_validators(&file, None, &mut response);
+    if conditional::is_not_modified(&req, &file, None) {
+        return Ok(response.status(StatusCode::NOT_MODIFIED).finish());
+    }
+    ranges::respond(&req, &file, response).await
 }
@@ -6,7 +6,7 @@ use serde::Deserialize;
 
 use crate::errors::Result;
 use crate::metadata::Metadata;
-use crate::utilities::{fetch, get_media_size, Embed, Image, ImageSize, Video};
+use crate::utilities::{fetch, get_media_size, Embed, FetchOptions, Image, ImageSize, Video};
 
 #[derive(Deserialize)]
 pub struct Parameters {
@@ -15,7 +15,7 @@ pub struct Parameters {
 
 pub async fn handle(info: Query<Parameters>) -> Result<impl Responder> {
     let url = info.into_inner().url;
-    let (resp, mime) = fetch(&url).await?;
+    let (resp, mime) = fetch(&url, &FetchOptions::default()).await?;
     match (mime.type_(), mime.subtype()) {
         (_, mime::HTML) => {
             let mut metadata = Metadata::from(resp, url.to_string()).await?;
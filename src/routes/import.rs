@@ -0,0 +1,79 @@
+use std::time::Duration;
+
+use actix_web::{web, Responder};
+use futures::StreamExt;
+use reqwest::Url;
+use serde::Deserialize;
+use validator::Validate;
+
+use crate::authentication::Identity;
+use crate::errors::{Error, Result};
+use crate::ingest::{ingest, Spool};
+use crate::routes::upload::UploadResponse;
+use crate::stores::Store;
+use crate::utilities::{fetch, FetchOptions};
+
+/// Slowest transfer rate, in bytes per second, an import is given time for.
+const MIN_TRANSFER_RATE: u64 = 1024 * 1024;
+const MIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Deserialize, Validate)]
+pub struct ImportRequest {
+    #[validate(url, length(max = 2048))]
+    url: String,
+    #[validate(length(min = 1, max = 256))]
+    filename: Option<String>,
+}
+
+/// Names the file after the last segment of the URL path.
+fn filename_from_url(url: &str) -> Option<String> {
+    Url::parse(url)
+        .ok()?
+        .path_segments()?
+        .next_back()
+        .filter(|segment| !segment.is_empty())
+        .map(str::to_string)
+}
+
+pub async fn handle(
+    identity: Option<Identity>,
+    path: web::Path<String>,
+    body: web::Json<ImportRequest>,
+) -> Result<impl Responder> {
+    let store_id = path.into_inner();
+    let store = Store::get(&store_id)?;
+    let uploader = store.authorize_upload(identity.as_ref())?;
+    body.validate().map_err(|_| Error::ValidationFailed)?;
+    let ImportRequest { url, filename } = body.into_inner();
+    // Large bodies may take a while to arrive. The contents are sniffed once downloaded, so
+    // the remote content type does not matter.
+    let options = FetchOptions {
+        timeout: MIN_TIMEOUT.max(Duration::from_secs(
+            store.max_size as u64 / MIN_TRANSFER_RATE,
+        )),
+        require_content_type: false,
+    };
+    let (response, _) = fetch(&url, &options).await?;
+    if response
+        .content_length()
+        .is_some_and(|length| length > store.max_size as u64)
+    {
+        return Err(Error::FileTooLarge {
+            max_size: store.max_size,
+        });
+    }
+    let mut spool = Spool::new(store.max_size)?;
+    let mut stream = response.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        spool
+            .write(&chunk.map_err(|_| Error::InternalRequestFailed)?)
+            .await?;
+    }
+    let (tmp, fingerprint) = spool.finish().await?;
+    let filename = filename
+        .or_else(|| filename_from_url(&url))
+        .unwrap_or_else(|| "file".to_string());
+    let id = ulid::Ulid::new().to_string();
+    let file = ingest(&store_id, id, filename, tmp.path(), fingerprint, uploader).await?;
+    Ok(web::Json(UploadResponse { id: file.id }))
+}
@@ -1,6 +1,29 @@
+pub mod attach;
+pub mod delete;
+pub mod detach;
 pub mod download;
 pub mod embed;
+pub mod import;
+pub mod presign;
 pub mod proxy;
 pub mod serve;
 pub mod service;
+pub mod sign;
+pub mod tus;
 pub mod upload;
+pub mod uploader;
+pub mod usage;
+
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+
+#[derive(Deserialize, Validate)]
+pub struct BulkRequest {
+    #[validate(length(min = 1, max = 100))]
+    pub ids: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct BulkResponse {
+    pub count: u64,
+}
@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+
+use actix_web::{web, Responder};
+use mongodb::bson::doc;
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+use validator::Validate;
+
+use crate::authentication::Identity;
+use crate::errors::{Error, Result};
+use crate::files::get_collection;
+use crate::ingest::ingest_object;
+use crate::presigned::PresignedObject;
+use crate::routes::upload::UploadResponse;
+use crate::storage::get_backend;
+use crate::stores::Store;
+
+#[derive(Serialize)]
+pub struct PresignResponse {
+    id: String,
+    url: String,
+    fields: HashMap<String, String>,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct FinalizeRequest {
+    #[validate(length(min = 1, max = 256))]
+    filename: String,
+}
+
+pub async fn handle(identity: Option<Identity>, path: web::Path<String>) -> Result<impl Responder> {
+    let store_id = path.into_inner();
+    let store = Store::get(&store_id)?;
+    let uploader = store.authorize_upload(identity.as_ref())?;
+    // The object is uploaded under the id of the file it becomes.
+    let id = Ulid::new().to_string();
+    PresignedObject::create(&id, &store_id, uploader).await?;
+    let upload = get_backend(&store_id)?
+        .presign_upload(&id, store.max_size as u64)
+        .await?;
+    Ok(web::Json(PresignResponse {
+        id,
+        url: upload.url,
+        fields: upload.fields,
+    }))
+}
+
+pub async fn finalize(
+    identity: Option<Identity>,
+    path: web::Path<(String, String)>,
+    body: web::Json<FinalizeRequest>,
+) -> Result<impl Responder> {
+    let (store_id, id) = path.into_inner();
+    Store::get(&store_id)?.authorize_upload(identity.as_ref())?;
+    body.validate().map_err(|_| Error::ValidationFailed)?;
+    Ulid::from_string(&id).map_err(|_| Error::InvalidData)?;
+    // Only the caller the upload was presigned for may finalize it, and it is theirs.
+    let presigned = PresignedObject::find(&id, &store_id).await?;
+    presigned.check_owner(identity.as_ref())?;
+    if get_collection()
+        .find_one(doc! { "id": &id })
+        .await
+        .map_err(|_| Error::DatabaseError)?
+        .is_some()
+    {
+        return Err(Error::InvalidData);
+    }
+    let file = ingest_object(
+        &store_id,
+        id,
+        body.into_inner().filename,
+        presigned.uploader.clone(),
+    )
+    .await?;
+    presigned.delete().await.ok();
+    Ok(web::Json(UploadResponse { id: file.id }))
+}
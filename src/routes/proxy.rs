@@ -2,7 +2,7 @@ use actix_web::{web::Query, HttpResponse, Responder};
 use serde::Deserialize;
 
 use crate::errors::{Error, Result};
-use crate::utilities::fetch;
+use crate::utilities::{fetch, FetchOptions};
 
 #[derive(Deserialize)]
 pub struct Parameters {
@@ -11,7 +11,7 @@ pub struct Parameters {
 
 pub async fn handle(info: Query<Parameters>) -> Result<impl Responder> {
     let url = info.into_inner().url;
-    let (resp, mime) = fetch(&url).await?;
+    let (resp, mime) = fetch(&url, &FetchOptions::default()).await?;
     if matches!(mime.type_(), mime::IMAGE | mime::VIDEO) {
         let body = resp
             .bytes()
@@ -1,12 +1,35 @@
+use actix_web::http::header::{Accept, Quality, VARY};
+use actix_web::http::StatusCode;
 use actix_web::web;
-use actix_web::{HttpResponse, Responder};
-use mongodb::bson::doc;
+use actix_web::{HttpMessage, HttpRequest, HttpResponse};
 use serde::Deserialize;
 
-use crate::constants::CACHE_CONTROL;
+use crate::conditional;
 use crate::errors::Result;
-use crate::files::File;
+use crate::files::{File, FileMetadata};
+use crate::ranges;
+use crate::signing::Signature;
 use crate::stores::Store;
+use crate::transform::{Fit, Gravity, ImageFormat};
+
+/// Picks the best format the client accepts, falling back to the family of the original.
+fn negotiate_format(req: &HttpRequest, original: &str) -> ImageFormat {
+    let accepted = req
+        .get_header::<Accept>()
+        .unwrap_or_else(|| Accept(Vec::new()));
+    for format in [ImageFormat::Avif, ImageFormat::Webp] {
+        if accepted.iter().any(|item| {
+            item.quality > Quality::ZERO && item.item.essence_str() == format.content_type()
+        }) {
+            return format;
+        }
+    }
+    if original == "image/jpeg" {
+        ImageFormat::Jpeg
+    } else {
+        ImageFormat::Png
+    }
+}
 
 #[derive(Deserialize)]
 pub struct Resize {
@@ -14,25 +37,133 @@ pub struct Resize {
     pub width: Option<isize>,
     pub height: Option<isize>,
     pub max_side: Option<isize>,
+    pub fit: Option<Fit>,
+    pub gravity: Option<Gravity>,
+    pub focus_x: Option<f64>,
+    pub focus_y: Option<f64>,
+    pub dpr: Option<f64>,
+    pub quality: Option<u8>,
+    pub format: Option<ImageFormat>,
+    pub lossless: Option<bool>,
+}
+
+impl Resize {
+    pub fn is_empty(&self) -> bool {
+        self.size.is_none()
+            && self.width.is_none()
+            && self.height.is_none()
+            && self.max_side.is_none()
+            && self.fit.is_none()
+            && self.gravity.is_none()
+            && self.focus_x.is_none()
+            && self.focus_y.is_none()
+            && self.dpr.is_none()
+            && self.quality.is_none()
+            && self.format.is_none()
+    }
+
+    pub fn is_lossless(&self) -> bool {
+        match self.format {
+            Some(ImageFormat::Png) => true,
+            Some(ImageFormat::Webp) => self.lossless.unwrap_or(false),
+            _ => false,
+        }
+    }
+
+    /// Identifies the output encoding, used in variant cache keys and entity tags.
+    pub fn encoding(&self) -> String {
+        let format = self.format.unwrap_or(ImageFormat::Png);
+        if format == ImageFormat::Webp && self.is_lossless() {
+            format!("{}-lossless", format.extension())
+        } else {
+            format.extension().to_string()
+        }
+    }
+}
+
+fn disposition(content_type: &str) -> &'static str {
+    match content_type {
+        "image/jpeg" | "image/png" | "image/gif" | "image/webp" | "image/avif" | "video/mp4"
+        | "video/webm" | "video/webp" | "audio/quicktime" | "audio/mpeg" => "inline",
+        _ => "attachment",
+    }
 }
 
 pub async fn handle(
+    req: HttpRequest,
     path: web::Path<(String, String)>,
     resize: web::Query<Resize>,
-) -> Result<impl Responder> {
+    signature: web::Query<Signature>,
+) -> Result<HttpResponse> {
     let (store_id, id) = path.into_inner();
-    Store::get(&store_id)?;
+    let cache_control = Store::get(&store_id)?.authorize_read(&store_id, &id, &signature)?;
     let file = File::find(&id, &store_id).await?;
-    let (contents, content_type) = file.fetch(Some(resize.0)).await?;
+    let mut response = HttpResponse::Ok();
+    response.insert_header(("Cache-Control", cache_control));
+    if resize.is_empty() || !matches!(file.metadata, FileMetadata::Image { .. }) {
+        conditional::insert_validators(&file, None, &mut response);
+        if conditional::is_not_modified(&req, &file, None) {
+            return Ok(response.status(StatusCode::NOT_MODIFIED).finish());
+        }
+        response.insert_header(("Content-Disposition", disposition(&file.content_type)));
+        return ranges::respond(&req, &file, response).await;
+    }
+    let mut resize = resize.into_inner();
+    if resize.format.is_none() {
+        resize.format = Some(negotiate_format(&req, &file.content_type));
+        response.insert_header((VARY, "Accept"));
+    }
+    let encoding = resize.encoding();
+    conditional::insert_validators(&file, Some(&encoding), &mut response);
+    if conditional::is_not_modified(&req, &file, Some(&encoding)) {
+        return Ok(response.status(StatusCode::NOT_MODIFIED).finish());
+    }
+    let (contents, content_type) = file.fetch(&resize).await?;
     let content_type = content_type.unwrap_or(file.content_type);
-    let disposition = match content_type.as_ref() {
-        "image/jpeg" | "image/png" | "image/gif" | "image/webp" | "video/mp4" | "video/webm"
-        | "video/webp" | "audio/quicktime" | "audio/mpeg" => "inline",
-        _ => "attachment",
-    };
-    Ok(HttpResponse::Ok()
-        .insert_header(("Content-Disposition", disposition))
-        .insert_header(("Cache-Control", CACHE_CONTROL))
+    Ok(response
+        .insert_header(("Content-Disposition", disposition(&content_type)))
         .content_type(content_type)
         .body(contents))
 }
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::ACCEPT;
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    fn negotiate(accept: Option<&str>, original: &str) -> ImageFormat {
+        let mut req = TestRequest::default();
+        if let Some(accept) = accept {
+            req = req.insert_header((ACCEPT, accept));
+        }
+        negotiate_format(&req.to_http_request(), original)
+    }
+
+    #[test]
+    fn prefers_modern_formats() {
+        let accept = "image/avif,image/webp,image/*,*/*;q=0.8";
+        assert_eq!(negotiate(Some(accept), "image/png"), ImageFormat::Avif);
+        assert_eq!(
+            negotiate(Some("image/webp,*/*"), "image/jpeg"),
+            ImageFormat::Webp
+        );
+    }
+
+    #[test]
+    fn skips_refused_formats() {
+        assert_eq!(
+            negotiate(Some("image/avif;q=0,image/webp"), "image/png"),
+            ImageFormat::Webp
+        );
+    }
+
+    #[test]
+    fn falls_back_to_original_family() {
+        assert_eq!(negotiate(None, "image/jpeg"), ImageFormat::Jpeg);
+        assert_eq!(negotiate(Some("*/*"), "image/jpeg"), ImageFormat::Jpeg);
+        assert_eq!(negotiate(Some("image/*"), "image/gif"), ImageFormat::Png);
+        assert_eq!(negotiate(None, "image/webp"), ImageFormat::Png);
+    }
+}
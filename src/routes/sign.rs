@@ -0,0 +1,37 @@
+use actix_web::{web, Responder};
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+
+use crate::authentication::Service;
+use crate::errors::{Error, Result};
+use crate::files::File;
+use crate::signing::sign_url;
+use crate::stores::Store;
+
+const DEFAULT_TTL: u64 = 3600;
+
+#[derive(Deserialize, Validate)]
+pub struct SignRequest {
+    /// Seconds the URL remains valid for.
+    #[validate(range(min = 1, max = 604800))]
+    ttl: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct SignResponse {
+    url: String,
+    expires: u64,
+}
+
+pub async fn handle(
+    _: Service,
+    path: web::Path<(String, String)>,
+    body: web::Json<SignRequest>,
+) -> Result<impl Responder> {
+    let (store_id, id) = path.into_inner();
+    Store::get(&store_id)?;
+    body.validate().map_err(|_| Error::ValidationFailed)?;
+    File::find(&id, &store_id).await?;
+    let (url, expires) = sign_url(&store_id, &id, body.ttl.unwrap_or(DEFAULT_TTL))?;
+    Ok(web::Json(SignResponse { url, expires }))
+}
@@ -0,0 +1,194 @@
+use actix_web::http::header::{CACHE_CONTROL, CONTENT_TYPE, LOCATION};
+use actix_web::{web, HttpRequest, HttpResponse, HttpResponseBuilder};
+use async_std::fs::OpenOptions;
+use async_std::io::WriteExt;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures::StreamExt;
+use log::warn;
+
+use crate::authentication::Identity;
+use crate::errors::{Error, Result};
+use crate::ingest::{ingest, Fingerprinter};
+use crate::stores::Store;
+use crate::uploads::Upload;
+use crate::usage::Usage;
+
+const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,termination";
+const OFFSET_CONTENT_TYPE: &str = "application/offset+octet-stream";
+
+fn header<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers().get(name)?.to_str().ok()
+}
+
+fn check_version(req: &HttpRequest) -> Result<()> {
+    match header(req, "Tus-Resumable") {
+        Some(TUS_VERSION) => Ok(()),
+        _ => Err(Error::UnsupportedVersion),
+    }
+}
+
+fn numeric_header(req: &HttpRequest, name: &str) -> Result<u64> {
+    header(req, name)
+        .and_then(|value| value.parse().ok())
+        .ok_or(Error::InvalidData)
+}
+
+/// Reads a value from `Upload-Metadata`, a list of keys with base64 encoded values.
+fn metadata(req: &HttpRequest, key: &str) -> Option<String> {
+    header(req, "Upload-Metadata")?
+        .split(',')
+        .filter_map(|pair| pair.trim().split_once(' '))
+        .find(|(name, _)| *name == key)
+        .and_then(|(_, value)| STANDARD.decode(value).ok())
+        .and_then(|value| String::from_utf8(value).ok())
+}
+
+/// Only the caller who created an upload may see or continue it.
+fn check_owner(upload: &Upload, identity: Option<&Identity>) -> Result<()> {
+    match &upload.uploader {
+        Some(uploader) if identity.map(|identity| &identity.subject) != Some(uploader) => {
+            Err(Error::Forbidden)
+        }
+        _ => Ok(()),
+    }
+}
+
+fn tus_response(mut response: HttpResponseBuilder) -> HttpResponseBuilder {
+    response.insert_header(("Tus-Resumable", TUS_VERSION));
+    response
+}
+
+pub async fn options(path: web::Path<String>) -> Result<HttpResponse> {
+    let store = Store::get(&path.into_inner())?;
+    Ok(tus_response(HttpResponse::NoContent())
+        .insert_header(("Tus-Version", TUS_VERSION))
+        .insert_header(("Tus-Extension", TUS_EXTENSIONS))
+        .insert_header(("Tus-Max-Size", store.max_size.to_string()))
+        .finish())
+}
+
+pub async fn create(
+    req: HttpRequest,
+    identity: Option<Identity>,
+    path: web::Path<String>,
+) -> Result<HttpResponse> {
+    check_version(&req)?;
+    let store_id = path.into_inner();
+    let store = Store::get(&store_id)?;
+    let uploader = store.authorize_upload(identity.as_ref())?;
+    let length = numeric_header(&req, "Upload-Length")?;
+    if length > store.max_size as u64 {
+        return Err(Error::FileTooLarge {
+            max_size: store.max_size,
+        });
+    }
+    Usage::check(store, &store_id, uploader.as_deref(), length).await?;
+    // Metadata is optional, and files of uploads without a name are named after their id.
+    let filename = metadata(&req, "filename").or_else(|| metadata(&req, "name"));
+    let upload = Upload::create(&store_id, length, filename, uploader).await?;
+    // The file takes the id of the upload once it completes.
+    Ok(tus_response(HttpResponse::Created())
+        .insert_header((LOCATION, format!("/stores/{}/tus/{}", store_id, upload.id)))
+        .finish())
+}
+
+pub async fn head(
+    req: HttpRequest,
+    identity: Option<Identity>,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse> {
+    check_version(&req)?;
+    let (store_id, id) = path.into_inner();
+    let upload = Upload::find(&id, &store_id).await?;
+    check_owner(&upload, identity.as_ref())?;
+    Ok(tus_response(HttpResponse::Ok())
+        .insert_header(("Upload-Offset", upload.offset.to_string()))
+        .insert_header(("Upload-Length", upload.length.to_string()))
+        .insert_header((CACHE_CONTROL, "no-store"))
+        .finish())
+}
+
+pub async fn patch(
+    req: HttpRequest,
+    identity: Option<Identity>,
+    path: web::Path<(String, String)>,
+    mut payload: web::Payload,
+) -> Result<HttpResponse> {
+    check_version(&req)?;
+    if header(&req, CONTENT_TYPE.as_str()) != Some(OFFSET_CONTENT_TYPE) {
+        return Err(Error::InvalidData);
+    }
+    let offset = numeric_header(&req, "Upload-Offset")?;
+    let (store_id, id) = path.into_inner();
+    let upload = Upload::find(&id, &store_id).await?;
+    check_owner(&upload, identity.as_ref())?;
+    // Held until the upload is ingested, so that a completed upload is only ingested once.
+    let _lock = upload.lock()?;
+    // Read the offset again now that no other request can advance it.
+    let mut upload = Upload::find(&id, &store_id).await?;
+    if offset != upload.offset {
+        return Err(Error::OffsetMismatch);
+    }
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(upload.path())
+        .await
+        .map_err(|_| Error::StorageError)?;
+    // Drop anything past the recorded offset left behind by an interrupted request.
+    file.set_len(offset)
+        .await
+        .map_err(|_| Error::StorageError)?;
+    let mut written = offset;
+    while let Some(chunk) = payload.next().await {
+        // Keep what arrived before the connection dropped so the client can resume from there.
+        let Ok(chunk) = chunk else {
+            break;
+        };
+        if written + chunk.len() as u64 > upload.length {
+            return Err(Error::InvalidData);
+        }
+        file.write_all(&chunk)
+            .await
+            .map_err(|_| Error::StorageError)?;
+        written += chunk.len() as u64;
+    }
+    file.flush().await.map_err(|_| Error::StorageError)?;
+    upload.advance(written).await?;
+
+    if upload.is_complete() {
+        let fingerprint = Fingerprinter::read(&upload.path()).await?;
+        let result = ingest(
+            &store_id,
+            upload.id.clone(),
+            upload.filename.clone().unwrap_or_else(|| upload.id.clone()),
+            &upload.path(),
+            fingerprint,
+            upload.uploader.clone(),
+        )
+        .await;
+        let id = upload.id.clone();
+        if upload.delete().await.is_err() {
+            warn!("Failed to delete completed upload {}", id);
+        }
+        result?;
+    }
+    Ok(tus_response(HttpResponse::NoContent())
+        .insert_header(("Upload-Offset", written.to_string()))
+        .finish())
+}
+
+pub async fn terminate(
+    req: HttpRequest,
+    identity: Option<Identity>,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse> {
+    check_version(&req)?;
+    let (store_id, id) = path.into_inner();
+    let upload = Upload::find(&id, &store_id).await?;
+    check_owner(&upload, identity.as_ref())?;
+    // Never delete the contents while a request is writing or ingesting them.
+    let _lock = upload.lock()?;
+    upload.delete().await?;
+    Ok(tus_response(HttpResponse::NoContent()).finish())
+}
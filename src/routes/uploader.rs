@@ -0,0 +1,66 @@
+use actix_web::{web, Responder};
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+
+use crate::authentication::{Credential, Identity, Service};
+use crate::errors::{Error, Result};
+use crate::files::{File, FileFilter};
+use crate::routes::BulkResponse;
+use crate::uploads::Upload;
+
+const DEFAULT_LIMIT: i64 = 50;
+
+#[derive(Deserialize, Validate)]
+pub struct ListParameters {
+    store: Option<String>,
+    #[serde(rename = "type")]
+    file_type: Option<String>,
+    attached: Option<bool>,
+    after: Option<String>,
+    #[validate(range(min = 1, max = 100))]
+    limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct ListResponse {
+    files: Vec<File>,
+    /// Passed as `after` to fetch the next page, if there may be one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next: Option<String>,
+}
+
+/// Lists the files of an uploader across stores. Callers may only list their own files,
+/// unless they are a trusted service.
+pub async fn list(
+    identity: Identity,
+    path: web::Path<String>,
+    query: web::Query<ListParameters>,
+) -> Result<impl Responder> {
+    let uploader = path.into_inner();
+    if !matches!(identity.credential, Credential::Service) && identity.subject != uploader {
+        return Err(Error::Forbidden);
+    }
+    query.validate().map_err(|_| Error::ValidationFailed)?;
+    let query = query.into_inner();
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+    let filter = FileFilter {
+        store: query.store,
+        file_type: query.file_type,
+        attached: query.attached,
+    };
+    let files = File::list_by_uploader(&uploader, &filter, query.after.as_deref(), limit).await?;
+    let next = match files.last() {
+        Some(file) if files.len() as i64 == limit => Some(file.id.clone()),
+        _ => None,
+    };
+    Ok(web::Json(ListResponse { files, next }))
+}
+
+/// Deletes everything uploaded by an uploader, including unfinished uploads, such as when
+/// their account is deleted.
+pub async fn delete(_: Service, path: web::Path<String>) -> Result<impl Responder> {
+    let uploader = path.into_inner();
+    Upload::delete_by_uploader(&uploader).await?;
+    let count = File::mark_deleted_by_uploader(&uploader).await?;
+    Ok(web::Json(BulkResponse { count }))
+}
@@ -0,0 +1,51 @@
+use actix_web::{web, Responder};
+use serde::Serialize;
+
+use crate::authentication::{Credential, Identity, Service};
+use crate::errors::{Error, Result};
+use crate::stores::{Quota, Store};
+use crate::usage::Usage;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageResponse {
+    bytes: u64,
+    files: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_files: Option<u64>,
+}
+
+impl UsageResponse {
+    fn new(usage: Usage, quota: &Quota) -> UsageResponse {
+        UsageResponse {
+            bytes: usage.bytes.max(0) as u64,
+            files: usage.files.max(0) as u64,
+            max_bytes: quota.max_bytes,
+            max_files: quota.max_files,
+        }
+    }
+}
+
+pub async fn handle(_: Service, path: web::Path<String>) -> Result<impl Responder> {
+    let store_id = path.into_inner();
+    let store = Store::get(&store_id)?;
+    let usage = Usage::get(&store_id, None).await?;
+    Ok(web::Json(UsageResponse::new(usage, &store.quota)))
+}
+
+/// Reports what an uploader stores in a store. Callers may only see their own usage, unless
+/// they are a trusted service.
+pub async fn handle_uploader(
+    identity: Identity,
+    path: web::Path<(String, String)>,
+) -> Result<impl Responder> {
+    let (store_id, uploader) = path.into_inner();
+    let store = Store::get(&store_id)?;
+    if !matches!(identity.credential, Credential::Service) && identity.subject != uploader {
+        return Err(Error::Forbidden);
+    }
+    let usage = Usage::get(&store_id, Some(&uploader)).await?;
+    Ok(web::Json(UsageResponse::new(usage, &store.uploader_quota)))
+}
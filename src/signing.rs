@@ -0,0 +1,135 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+
+use crate::environment::URL_SIGNING_SECRET;
+use crate::errors::{Error, Result};
+
+/// Query parameters carried by a signed URL.
+#[derive(Deserialize)]
+pub struct Signature {
+    pub expires: Option<u64>,
+    pub signature: Option<String>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// Signs access to a file until `expires`. The signature covers the file rather than the
+/// route, so the same URL parameters work for resized and downloaded copies.
+fn mac(store_id: &str, id: &str, expires: u64) -> Result<Hmac<Sha256>> {
+    let secret = URL_SIGNING_SECRET.as_ref().ok_or(Error::NotSupported)?;
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).map_err(|_| Error::UnknownError)?;
+    mac.update(format!("{}/{}:{}", store_id, id, expires).as_bytes());
+    Ok(mac)
+}
+
+/// Builds the URL serving a file until `ttl` seconds from now, returning it along with the
+/// time it expires.
+pub fn sign_url(store_id: &str, id: &str, ttl: u64) -> Result<(String, u64)> {
+    let expires = now() + ttl;
+    let signature = URL_SAFE_NO_PAD.encode(mac(store_id, id, expires)?.finalize().into_bytes());
+    let url = format!(
+        "/stores/{}/files/{}?expires={}&signature={}",
+        store_id, id, expires, signature
+    );
+    Ok((url, expires))
+}
+
+/// Checks the signature of a request for a file, returning how many seconds it remains valid.
+pub fn verify(store_id: &str, id: &str, signature: &Signature) -> Result<u64> {
+    let (Some(expires), Some(encoded)) = (signature.expires, &signature.signature) else {
+        return Err(Error::Unauthorized);
+    };
+    let remaining = expires.checked_sub(now()).ok_or(Error::Forbidden)?;
+    let decoded = URL_SAFE_NO_PAD
+        .decode(encoded)
+        .map_err(|_| Error::Forbidden)?;
+    mac(store_id, id, expires)
+        .map_err(|_| Error::Forbidden)?
+        .verify_slice(&decoded)
+        .map_err(|_| Error::Forbidden)?;
+    Ok(remaining)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signature(url: &str) -> Signature {
+        let query = url.split_once('?').unwrap().1;
+        actix_web::web::Query::<Signature>::from_query(query)
+            .unwrap()
+            .into_inner()
+    }
+
+    fn setup() {
+        std::env::set_var("URL_SIGNING_SECRET", "secret");
+    }
+
+    #[test]
+    fn verifies_signed_url() {
+        setup();
+        let (url, expires) = sign_url("private", "file", 60).unwrap();
+        let signature = signature(&url);
+        assert_eq!(signature.expires, Some(expires));
+        assert!(verify("private", "file", &signature).unwrap() <= 60);
+    }
+
+    #[test]
+    fn rejects_other_files_and_tampering() {
+        setup();
+        let (url, _) = sign_url("private", "file", 60).unwrap();
+        let mut signature = signature(&url);
+        assert!(matches!(
+            verify("private", "other", &signature),
+            Err(Error::Forbidden)
+        ));
+        assert!(matches!(
+            verify("public", "file", &signature),
+            Err(Error::Forbidden)
+        ));
+        signature.expires = signature.expires.map(|expires| expires + 3600);
+        assert!(matches!(
+            verify("private", "file", &signature),
+            Err(Error::Forbidden)
+        ));
+    }
+
+    #[test]
+    fn rejects_expired_and_missing_signatures() {
+        setup();
+        let expires = now() - 1;
+        let signature = Signature {
+            expires: Some(expires),
+            signature: Some(
+                URL_SAFE_NO_PAD.encode(
+                    mac("private", "file", expires)
+                        .unwrap()
+                        .finalize()
+                        .into_bytes(),
+                ),
+            ),
+        };
+        assert!(matches!(
+            verify("private", "file", &signature),
+            Err(Error::Forbidden)
+        ));
+        let missing = Signature {
+            expires: None,
+            signature: None,
+        };
+        assert!(matches!(
+            verify("private", "file", &missing),
+            Err(Error::Unauthorized)
+        ));
+    }
+}
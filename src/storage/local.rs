@@ -0,0 +1,226 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use async_std::fs;
+use async_std::io::{prelude::SeekExt, ErrorKind, ReadExt, SeekFrom};
+use async_std::stream::StreamExt;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream;
+use ulid::Ulid;
+
+use crate::errors::{Error, Result};
+
+use super::{ByteStream, Object, StorageBackend};
+
+const CHUNK_SIZE: usize = 65536;
+/// Ending of the names contents are written under before being moved into place.
+const PARTIAL_SUFFIX: &str = ".partial";
+
+pub struct LocalBackend {
+    root: PathBuf,
+}
+
+impl LocalBackend {
+    pub fn new(root: String) -> std::io::Result<LocalBackend> {
+        std::fs::create_dir_all(&root)?;
+        Ok(LocalBackend { root: root.into() })
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf> {
+        if key.is_empty() || key.split('/').any(|part| part.is_empty() || part == "..") {
+            return Err(Error::StorageError);
+        }
+        Ok(self.root.join(key))
+    }
+
+    /// Hidden path next to `path` to write to, so that an object never exists with only part
+    /// of its contents.
+    fn partial_path(path: &Path) -> PathBuf {
+        let mut name = OsString::from(".");
+        name.push(path.file_name().unwrap_or_default());
+        name.push(format!(".{}{}", Ulid::new(), PARTIAL_SUFFIX));
+        path.with_file_name(name)
+    }
+
+    /// Moves the contents written to `partial` into place once they are complete.
+    async fn finish(partial: &Path, path: &Path, written: std::io::Result<()>) -> Result<()> {
+        if written.is_ok() && fs::rename(partial, path).await.is_ok() {
+            return Ok(());
+        }
+        fs::remove_file(partial).await.ok();
+        Err(Error::StorageError)
+    }
+}
+
+#[async_trait]
+impl StorageBackend for LocalBackend {
+    async fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .await
+                .map_err(|_| Error::StorageError)?;
+        }
+        let partial = LocalBackend::partial_path(&path);
+        let written = fs::write(&partial, data).await;
+        LocalBackend::finish(&partial, &path, written).await
+    }
+
+    async fn put_file(&self, key: &str, source: &Path) -> Result<()> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .await
+                .map_err(|_| Error::StorageError)?;
+        }
+        let partial = LocalBackend::partial_path(&path);
+        let written = fs::copy(source, &partial).await.map(|_| ());
+        LocalBackend::finish(&partial, &path, written).await
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>> {
+        fs::read(self.path(key)?)
+            .await
+            .map_err(|_| Error::StorageError)
+    }
+
+    async fn stream(&self, key: &str, range: Option<(u64, u64)>) -> Result<ByteStream> {
+        let mut file = fs::File::open(self.path(key)?)
+            .await
+            .map_err(|_| Error::StorageError)?;
+        let length = match range {
+            Some((start, end)) => {
+                file.seek(SeekFrom::Start(start))
+                    .await
+                    .map_err(|_| Error::StorageError)?;
+                end - start + 1
+            }
+            None => u64::MAX,
+        };
+        Ok(Box::pin(stream::try_unfold(
+            file.take(length),
+            |mut reader| async move {
+                let mut chunk = vec![0; CHUNK_SIZE];
+                let read = reader
+                    .read(&mut chunk)
+                    .await
+                    .map_err(|_| Error::StorageError)?;
+                if read == 0 {
+                    return Ok(None);
+                }
+                chunk.truncate(read);
+                Ok(Some((Bytes::from(chunk), reader)))
+            },
+        )))
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let path = self.path(key)?;
+        match fs::remove_file(&path).await {
+            Ok(()) => {}
+            // Deleting is idempotent so that retried deletes succeed.
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(_) => return Err(Error::StorageError),
+        }
+        // Clean up directories left empty by nested keys, stopping at the first non-empty one.
+        let mut directory = path.parent();
+        while let Some(parent) = directory.filter(|parent| *parent != self.root) {
+            if fs::remove_dir(parent).await.is_err() {
+                break;
+            }
+            directory = parent.parent();
+        }
+        Ok(())
+    }
+
+    async fn head(&self, key: &str) -> Result<Option<Object>> {
+        match fs::metadata(self.path(key)?).await {
+            Ok(metadata) if metadata.is_file() => Ok(Some(Object {
+                key: key.to_string(),
+                size: metadata.len(),
+            })),
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(_) => Err(Error::StorageError),
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<Object>> {
+        let mut objects = Vec::new();
+        let mut directories = vec![self.root.clone()];
+        while let Some(directory) = directories.pop() {
+            let mut entries = fs::read_dir(&directory)
+                .await
+                .map_err(|_| Error::StorageError)?;
+            while let Some(entry) = entries.next().await {
+                let entry = entry.map_err(|_| Error::StorageError)?;
+                let metadata = entry.metadata().await.map_err(|_| Error::StorageError)?;
+                let path: PathBuf = entry.path().into();
+                if metadata.is_dir() {
+                    directories.push(path);
+                    continue;
+                }
+                let Ok(relative) = path.strip_prefix(&self.root) else {
+                    continue;
+                };
+                let key = relative
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                if name.starts_with('.') && name.ends_with(PARTIAL_SUFFIX) {
+                    continue;
+                }
+                if key.starts_with(prefix) {
+                    objects.push(Object {
+                        key,
+                        size: metadata.len(),
+                    });
+                }
+            }
+        }
+        Ok(objects)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn writes_objects_whole() {
+        let root = tempfile::tempdir().unwrap();
+        let backend = LocalBackend::new(root.path().to_string_lossy().into_owned()).unwrap();
+        backend
+            .put("blobs/store/hash", b"hello world")
+            .await
+            .unwrap();
+        let source = root.path().join("source");
+        std::fs::write(&source, b"hi").unwrap();
+        backend.put_file("file", &source).await.unwrap();
+
+        assert_eq!(
+            backend.get("blobs/store/hash").await.unwrap(),
+            b"hello world"
+        );
+        assert_eq!(backend.head("file").await.unwrap().unwrap().size, 2);
+        // A write that never completes leaves no object behind.
+        std::fs::write(
+            root.path().join(".file.01ARZ3NDEKTSV4RRFFQ69G5FAV.partial"),
+            b"h",
+        )
+        .unwrap();
+        let mut keys: Vec<_> = backend
+            .list("")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|object| object.key)
+            .collect();
+        keys.sort();
+        assert_eq!(keys, ["blobs/store/hash", "file", "source"]);
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::RwLock;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream;
+
+use crate::errors::{Error, Result};
+
+use super::{ByteStream, Object, StorageBackend};
+
+#[derive(Default)]
+pub struct MemoryBackend {
+    objects: RwLock<HashMap<String, Vec<u8>>>,
+}
+
+#[async_trait]
+impl StorageBackend for MemoryBackend {
+    async fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        self.objects
+            .write()
+            .map_err(|_| Error::StorageError)?
+            .insert(key.to_string(), data.to_vec());
+        Ok(())
+    }
+
+    async fn put_file(&self, key: &str, path: &Path) -> Result<()> {
+        let data = async_std::fs::read(path)
+            .await
+            .map_err(|_| Error::StorageError)?;
+        self.put(key, &data).await
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>> {
+        self.objects
+            .read()
+            .map_err(|_| Error::StorageError)?
+            .get(key)
+            .cloned()
+            .ok_or(Error::StorageError)
+    }
+
+    async fn stream(&self, key: &str, range: Option<(u64, u64)>) -> Result<ByteStream> {
+        let objects = self.objects.read().map_err(|_| Error::StorageError)?;
+        let data = objects.get(key).ok_or(Error::StorageError)?;
+        let contents = match range {
+            Some((start, end)) => data
+                .get(start as usize..=end as usize)
+                .ok_or(Error::StorageError)?,
+            None => data,
+        };
+        Ok(Box::pin(stream::once(futures::future::ok(
+            Bytes::copy_from_slice(contents),
+        ))))
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.objects
+            .write()
+            .map_err(|_| Error::StorageError)?
+            .remove(key);
+        Ok(())
+    }
+
+    async fn head(&self, key: &str) -> Result<Option<Object>> {
+        Ok(self
+            .objects
+            .read()
+            .map_err(|_| Error::StorageError)?
+            .get(key)
+            .map(|data| Object {
+                key: key.to_string(),
+                size: data.len() as u64,
+            }))
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<Object>> {
+        Ok(self
+            .objects
+            .read()
+            .map_err(|_| Error::StorageError)?
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .map(|(key, data)| Object {
+                key: key.clone(),
+                size: data.len() as u64,
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::TryStreamExt;
+
+    use super::*;
+
+    async fn read(stream: ByteStream) -> Vec<u8> {
+        stream
+            .try_fold(Vec::new(), |mut contents, chunk| async move {
+                contents.extend_from_slice(&chunk);
+                Ok(contents)
+            })
+            .await
+            .unwrap()
+    }
+
+    #[async_std::test]
+    async fn round_trip() {
+        let backend = MemoryBackend::default();
+        backend.put("file", b"hello world").await.unwrap();
+        backend.put("variants/file/small", b"hi").await.unwrap();
+
+        assert_eq!(backend.get("file").await.unwrap(), b"hello world");
+        assert_eq!(
+            read(backend.stream("file", None).await.unwrap()).await,
+            b"hello world"
+        );
+        assert_eq!(
+            read(backend.stream("file", Some((6, 10))).await.unwrap()).await,
+            b"world"
+        );
+        assert_eq!(backend.head("file").await.unwrap().unwrap().size, 11);
+        let listed = backend.list("variants/file/").await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].key, "variants/file/small");
+
+        let keys = vec!["file".to_string(), "variants/file/small".to_string()];
+        assert!(backend.delete_many(&keys).await.unwrap().is_empty());
+        assert!(backend.head("file").await.unwrap().is_none());
+        assert!(backend.get("file").await.is_err());
+        // Deleting a missing object succeeds.
+        assert!(backend.delete("file").await.is_ok());
+    }
+}
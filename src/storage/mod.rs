@@ -0,0 +1,120 @@
+pub mod local;
+pub mod memory;
+pub mod s3;
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::pin::Pin;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::Stream;
+use log::info;
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+
+use crate::environment::{LOCAL_STORAGE_PATH, USE_S3};
+use crate::errors::{Error, Result};
+use crate::stores::{Backend, Store};
+
+pub type ByteStream = Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>;
+
+#[derive(Debug, Clone)]
+pub struct Object {
+    pub key: String,
+    pub size: u64,
+}
+
+/// A form upload going straight to the backend, bypassing the server.
+#[derive(Debug, Serialize)]
+pub struct PresignedUpload {
+    pub url: String,
+    pub fields: HashMap<String, String>,
+}
+
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    async fn put(&self, key: &str, data: &[u8]) -> Result<()>;
+    async fn put_file(&self, key: &str, path: &Path) -> Result<()>;
+    async fn get(&self, key: &str) -> Result<Vec<u8>>;
+    /// Streams the object, or only the inclusive byte range if one is given.
+    async fn stream(&self, key: &str, range: Option<(u64, u64)>) -> Result<ByteStream>;
+    async fn delete(&self, key: &str) -> Result<()>;
+    /// Deletes several objects, returning the keys that could not be deleted. Deleting a
+    /// missing object succeeds.
+    async fn delete_many(&self, keys: &[String]) -> Result<Vec<String>> {
+        let mut failed = Vec::new();
+        for key in keys {
+            if self.delete(key).await.is_err() {
+                failed.push(key.clone());
+            }
+        }
+        Ok(failed)
+    }
+    async fn head(&self, key: &str) -> Result<Option<Object>>;
+    async fn list(&self, prefix: &str) -> Result<Vec<Object>>;
+    /// Issues a form upload of at most `max_size` bytes to the key, if the backend can be
+    /// uploaded to directly.
+    async fn presign_upload(&self, _key: &str, _max_size: u64) -> Result<PresignedUpload> {
+        Err(Error::NotSupported)
+    }
+    /// Issues a short lived URL to read the object, if the backend can be read directly.
+    async fn presign_download(&self, _key: &str) -> Result<String> {
+        Err(Error::NotSupported)
+    }
+    /// Aborts uploads started longer than `age` ago that never completed, returning how many
+    /// were aborted.
+    async fn abort_stale_uploads(&self, _age: Duration) -> Result<u64> {
+        Ok(0)
+    }
+}
+
+static BACKEND_MAP: OnceCell<HashMap<String, Box<dyn StorageBackend>>> = OnceCell::new();
+
+pub fn get_backend(store_id: &str) -> Result<&'static dyn StorageBackend> {
+    BACKEND_MAP
+        .get()
+        .expect("Failed to get global storage backends")
+        .get(store_id)
+        .map(|backend| backend.as_ref())
+        .ok_or(Error::UnknownStore)
+}
+
+fn create_backend(store_id: &str, store: &Store) -> std::io::Result<Box<dyn StorageBackend>> {
+    let backend = match &store.backend {
+        Some(backend) => backend.clone(),
+        None if *USE_S3 => Backend::S3 { bucket: None },
+        None => Backend::Local { path: None },
+    };
+    Ok(match backend {
+        Backend::Local { path } => {
+            let path = path.unwrap_or_else(|| LOCAL_STORAGE_PATH.to_string());
+            info!("Store {} uses local storage at {}", store_id, path);
+            Box::new(local::LocalBackend::new(path)?)
+        }
+        Backend::S3 { bucket } => {
+            let bucket = bucket.unwrap_or_else(|| store_id.to_string());
+            info!("Store {} uses S3 bucket {}", store_id, bucket);
+            Box::new(
+                s3::S3Backend::new(&bucket)
+                    .map_err(|_| std::io::Error::other("Failed to create S3 bucket"))?,
+            )
+        }
+        Backend::Memory => {
+            info!("Store {} uses in-memory storage", store_id);
+            Box::new(memory::MemoryBackend::default())
+        }
+    })
+}
+
+pub fn load_backends(stores: &HashMap<String, Store>) -> std::io::Result<()> {
+    let mut backends = HashMap::new();
+    for (store_id, store) in stores {
+        backends.insert(store_id.clone(), create_backend(store_id, store)?);
+    }
+    if BACKEND_MAP.set(backends).is_err() {
+        panic!("Failed to set global storage backends");
+    }
+    Ok(())
+}
@@ -0,0 +1,420 @@
+use std::path::Path;
+use std::time::Duration;
+
+use async_std::fs::File;
+use async_std::io::{prelude::SeekExt, ReadExt, SeekFrom};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures::{stream, StreamExt, TryStreamExt};
+use hmac::Mac;
+use lazy_static::lazy_static;
+use log::warn;
+use regex::Regex;
+use reqwest::header::{AUTHORIZATION, RANGE};
+use reqwest::{Client, StatusCode, Url};
+use s3::error::S3Error;
+use s3::post_policy::{PostPolicy, PostPolicyField, PostPolicyValue};
+use s3::serde_types::Part;
+use s3::signing::{self, HmacSha256};
+use s3::Bucket;
+use sha2::{Digest, Sha256};
+use time::format_description::well_known::Rfc3339;
+use time::format_description::FormatItem;
+use time::macros::format_description;
+use time::OffsetDateTime;
+
+use crate::environment::get_s3_bucket;
+use crate::errors::{Error, Result};
+
+use super::{ByteStream, Object, PresignedUpload, StorageBackend};
+
+const PRESIGN_EXPIRY: u32 = 60;
+const UPLOAD_EXPIRY: u32 = 3600;
+const CONTENT_TYPE: &str = "application/octet-stream";
+/// Files of at least this size are uploaded in parts.
+const MULTIPART_THRESHOLD: u64 = 16 * 1024 * 1024;
+const MIN_PART_SIZE: u64 = 8 * 1024 * 1024;
+const MAX_PARTS: u64 = 10000;
+const PART_CONCURRENCY: usize = 4;
+/// Most keys a single DeleteObjects request may name.
+const MAX_DELETE_KEYS: usize = 1000;
+const LONG_DATETIME: &[FormatItem<'static>] =
+    format_description!("[year][month][day]T[hour][minute][second]Z");
+
+lazy_static! {
+    static ref DELETE_ERROR_KEY: Regex = Regex::new(r"(?s)<Error>.*?<Key>(.*?)</Key>").unwrap();
+}
+
+pub struct S3Backend {
+    bucket: Bucket,
+    client: Client,
+}
+
+impl S3Backend {
+    pub fn new(bucket: &str) -> Result<S3Backend> {
+        Ok(S3Backend {
+            bucket: get_s3_bucket(bucket)?,
+            client: Client::new(),
+        })
+    }
+
+    async fn put_part(
+        &self,
+        key: &str,
+        source: &Path,
+        upload_id: &str,
+        number: u32,
+        range: (u64, u64),
+    ) -> Result<Part> {
+        let mut file = File::open(source).await.map_err(|_| Error::StorageError)?;
+        file.seek(SeekFrom::Start(range.0))
+            .await
+            .map_err(|_| Error::StorageError)?;
+        let mut chunk = vec![0; (range.1 - range.0) as usize];
+        file.read_exact(&mut chunk)
+            .await
+            .map_err(|_| Error::StorageError)?;
+        self.bucket
+            .put_multipart_chunk(chunk, &path(key), number, upload_id, CONTENT_TYPE)
+            .await
+            .map_err(|_| Error::StorageError)
+    }
+
+    async fn put_parts(
+        &self,
+        key: &str,
+        source: &Path,
+        size: u64,
+        upload_id: &str,
+    ) -> Result<Vec<Part>> {
+        // Parts are capped in number, so very large files need larger parts.
+        let part_size = MIN_PART_SIZE.max(size.div_ceil(MAX_PARTS));
+        let mut parts = stream::iter(0..size.div_ceil(part_size))
+            .map(|index| {
+                let start = index * part_size;
+                let end = (start + part_size).min(size);
+                self.put_part(key, source, upload_id, index as u32 + 1, (start, end))
+            })
+            .buffer_unordered(PART_CONCURRENCY)
+            .try_collect::<Vec<_>>()
+            .await?;
+        parts.sort_by_key(|part| part.part_number);
+        Ok(parts)
+    }
+
+    /// Uploads the file in parts sent in parallel, aborting the upload if any part fails.
+    async fn put_multipart(&self, key: &str, source: &Path, size: u64) -> Result<()> {
+        let upload = self
+            .bucket
+            .initiate_multipart_upload(&path(key), CONTENT_TYPE)
+            .await
+            .map_err(|_| Error::StorageError)?;
+        let result = match self.put_parts(key, source, size, &upload.upload_id).await {
+            Ok(parts) => self
+                .bucket
+                .complete_multipart_upload(&path(key), &upload.upload_id, parts)
+                .await
+                .map_err(|_| Error::StorageError)
+                .and_then(|response| match response.status_code() {
+                    200 => Ok(()),
+                    _ => Err(Error::StorageError),
+                }),
+            Err(error) => Err(error),
+        };
+        if result.is_err()
+            && self
+                .bucket
+                .abort_upload(&path(key), &upload.upload_id)
+                .await
+                .is_err()
+        {
+            warn!("Failed to abort multipart upload of {}", key);
+        }
+        result
+    }
+
+    /// Sends a DeleteObjects request, which rust-s3 lacks, returning the keys S3 failed to
+    /// delete.
+    async fn delete_objects(&self, keys: &[String]) -> Result<Vec<String>> {
+        let body = format!(
+            "<Delete><Quiet>true</Quiet>{}</Delete>",
+            keys.iter()
+                .map(|key| format!("<Object><Key>{}</Key></Object>", escape(key)))
+                .collect::<String>()
+        );
+        let url = Url::parse(&format!("{}/?delete", self.bucket.url()))
+            .map_err(|_| Error::StorageError)?;
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return Err(Error::StorageError),
+        };
+        let now = OffsetDateTime::now_utc();
+        let payload_hash = format!("{:x}", Sha256::digest(&body));
+        let mut headers = vec![
+            ("content-md5", STANDARD.encode(md5::compute(&body).0)),
+            ("host", host),
+            ("x-amz-content-sha256", payload_hash.clone()),
+            (
+                "x-amz-date",
+                now.format(LONG_DATETIME).map_err(|_| Error::StorageError)?,
+            ),
+        ];
+        let token = match self.bucket.security_token().await {
+            Ok(Some(token)) => Some(token),
+            _ => self.bucket.session_token().await.ok().flatten(),
+        };
+        if let Some(token) = token {
+            headers.push(("x-amz-security-token", token));
+        }
+
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_request = format!(
+            "POST\n{}\ndelete=\n{}\n\n{}\n{}",
+            signing::canonical_uri_string(&url),
+            headers
+                .iter()
+                .map(|(name, value)| format!("{}:{}", name, value))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            signed_headers,
+            payload_hash
+        );
+        let region = self.bucket.region();
+        let access_key = self.bucket.access_key().await.ok().flatten();
+        let secret_key = self.bucket.secret_key().await.ok().flatten();
+        let (Some(access_key), Some(secret_key)) = (access_key, secret_key) else {
+            return Err(Error::StorageError);
+        };
+        let key = signing::signing_key(&now, &secret_key, &region, "s3")
+            .map_err(|_| Error::StorageError)?;
+        let mut mac = HmacSha256::new_from_slice(&key).map_err(|_| Error::StorageError)?;
+        mac.update(
+            signing::string_to_sign(&now, &region, &canonical_request)
+                .map_err(|_| Error::StorageError)?
+                .as_bytes(),
+        );
+        let signature = format!("{:x}", mac.finalize().into_bytes());
+        let authorization =
+            signing::authorization_header(&access_key, &now, &region, &signed_headers, &signature)
+                .map_err(|_| Error::StorageError)?;
+
+        let mut request = self.client.post(url).header(AUTHORIZATION, authorization);
+        for (name, value) in headers {
+            // The client derives the host header from the URL itself.
+            if name != "host" {
+                request = request.header(name, value);
+            }
+        }
+        let response = request
+            .body(body)
+            .send()
+            .await
+            .map_err(|_| Error::StorageError)?;
+        if response.status() != StatusCode::OK {
+            return Err(Error::StorageError);
+        }
+        let text = response.text().await.map_err(|_| Error::StorageError)?;
+        Ok(DELETE_ERROR_KEY
+            .captures_iter(&text)
+            .map(|captures| unescape(&captures[1]))
+            .collect())
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn unescape(value: &str) -> String {
+    value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+fn path(key: &str) -> String {
+    format!("/{}", key)
+}
+
+#[async_trait]
+impl StorageBackend for S3Backend {
+    async fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        let response = self
+            .bucket
+            .put_object(path(key), data)
+            .await
+            .map_err(|_| Error::StorageError)?;
+        if response.status_code() != 200 {
+            return Err(Error::StorageError);
+        }
+        Ok(())
+    }
+
+    async fn put_file(&self, key: &str, source: &Path) -> Result<()> {
+        let size = async_std::fs::metadata(source)
+            .await
+            .map_err(|_| Error::StorageError)?
+            .len();
+        if size >= MULTIPART_THRESHOLD {
+            return self.put_multipart(key, source, size).await;
+        }
+        let data = async_std::fs::read(source)
+            .await
+            .map_err(|_| Error::StorageError)?;
+        self.put(key, &data).await
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>> {
+        let response = self
+            .bucket
+            .get_object(path(key))
+            .await
+            .map_err(|_| Error::StorageError)?;
+        if response.status_code() != 200 {
+            return Err(Error::StorageError);
+        }
+        Ok(response.into())
+    }
+
+    async fn stream(&self, key: &str, range: Option<(u64, u64)>) -> Result<ByteStream> {
+        let Some((start, end)) = range else {
+            let response = self
+                .bucket
+                .get_object_stream(path(key))
+                .await
+                .map_err(|_| Error::StorageError)?;
+            if response.status_code != 200 {
+                return Err(Error::StorageError);
+            }
+            return Ok(Box::pin(response.bytes.map_err(|_| Error::StorageError)));
+        };
+        // rust-s3 only buffers ranged reads, so stream them through a presigned request.
+        let url = self
+            .bucket
+            .presign_get(path(key), PRESIGN_EXPIRY, None)
+            .await
+            .map_err(|_| Error::StorageError)?;
+        let response = self
+            .client
+            .get(url)
+            .header(RANGE, format!("bytes={}-{}", start, end))
+            .send()
+            .await
+            .map_err(|_| Error::StorageError)?;
+        if response.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(Error::StorageError);
+        }
+        Ok(Box::pin(
+            response.bytes_stream().map_err(|_| Error::StorageError),
+        ))
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let response = self
+            .bucket
+            .delete_object(path(key))
+            .await
+            .map_err(|_| Error::StorageError)?;
+        if !matches!(response.status_code(), 200 | 204) {
+            return Err(Error::StorageError);
+        }
+        Ok(())
+    }
+
+    async fn delete_many(&self, keys: &[String]) -> Result<Vec<String>> {
+        let mut failed = Vec::new();
+        for chunk in keys.chunks(MAX_DELETE_KEYS) {
+            match self.delete_objects(chunk).await {
+                Ok(keys) => failed.extend(keys),
+                Err(_) => failed.extend_from_slice(chunk),
+            }
+        }
+        Ok(failed)
+    }
+
+    async fn head(&self, key: &str) -> Result<Option<Object>> {
+        match self.bucket.head_object(path(key)).await {
+            Ok((head, 200)) => Ok(Some(Object {
+                key: key.to_string(),
+                size: head.content_length.unwrap_or_default().max(0) as u64,
+            })),
+            Ok((_, 404)) | Err(S3Error::HttpFailWithBody(404, _)) => Ok(None),
+            _ => Err(Error::StorageError),
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<Object>> {
+        let results = self
+            .bucket
+            .list(prefix.to_string(), None)
+            .await
+            .map_err(|_| Error::StorageError)?;
+        Ok(results
+            .into_iter()
+            .flat_map(|result| result.contents)
+            .map(|object| Object {
+                key: object.key,
+                size: object.size,
+            })
+            .collect())
+    }
+
+    async fn presign_upload(&self, key: &str, max_size: u64) -> Result<PresignedUpload> {
+        let policy = PostPolicy::new(UPLOAD_EXPIRY)
+            .condition(PostPolicyField::Key, PostPolicyValue::Exact(key.into()))
+            .and_then(|policy| {
+                policy.condition(
+                    PostPolicyField::ContentLengthRange,
+                    PostPolicyValue::Range(0, max_size.min(u32::MAX as u64) as u32),
+                )
+            })
+            .map_err(|_| Error::StorageError)?;
+        let post = self
+            .bucket
+            .presign_post(policy)
+            .await
+            .map_err(|_| Error::StorageError)?;
+        Ok(PresignedUpload {
+            url: post.url,
+            fields: post.fields,
+        })
+    }
+
+    async fn presign_download(&self, key: &str) -> Result<String> {
+        self.bucket
+            .presign_get(path(key), PRESIGN_EXPIRY, None)
+            .await
+            .map_err(|_| Error::StorageError)
+    }
+
+    async fn abort_stale_uploads(&self, age: Duration) -> Result<u64> {
+        let cutoff = OffsetDateTime::now_utc() - age;
+        let results = self
+            .bucket
+            .list_multiparts_uploads(None, None)
+            .await
+            .map_err(|_| Error::StorageError)?;
+        let mut count = 0;
+        for upload in results.into_iter().flat_map(|result| result.uploads) {
+            let initiated = OffsetDateTime::parse(&upload.initiated, &Rfc3339)
+                .map_err(|_| Error::StorageError)?;
+            if initiated > cutoff {
+                continue;
+            }
+            self.bucket
+                .abort_upload(&path(&upload.key), &upload.id)
+                .await
+                .map_err(|_| Error::StorageError)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+}
@@ -1,11 +1,18 @@
+use image::Limits;
 use once_cell::sync::OnceCell;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::Read;
 
-use crate::environment::STORES;
+use crate::authentication::{Credential, Identity};
+use crate::constants::CACHE_CONTROL;
+use crate::environment::{STORES, URL_SIGNING_SECRET};
 use crate::errors::{Error, Result};
+use crate::ratelimit::RateLimit;
+use crate::signing::{self, Signature};
+use crate::storage::load_backends;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub enum ContentType {
@@ -14,11 +21,108 @@ pub enum ContentType {
     Audio,
 }
 
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Backend {
+    Local { path: Option<String> },
+    S3 { bucket: Option<String> },
+    Memory,
+}
+
+/// Limits on images processed by a store, protecting against decompression bombs.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+pub struct ImageLimits {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_height: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_pixels: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_decoded_bytes: Option<u64>,
+}
+
+impl ImageLimits {
+    pub fn check(&self, width: u64, height: u64) -> Result<()> {
+        if self.max_width.is_some_and(|max| width > max as u64)
+            || self.max_height.is_some_and(|max| height > max as u64)
+            || self.max_pixels.is_some_and(|max| width * height > max)
+        {
+            return Err(Error::ImageTooLarge);
+        }
+        Ok(())
+    }
+
+    pub fn decoder_limits(&self) -> Limits {
+        let mut limits = Limits::default();
+        limits.max_image_width = self.max_width;
+        limits.max_image_height = self.max_height;
+        if self.max_decoded_bytes.is_some() {
+            limits.max_alloc = self.max_decoded_bytes;
+        }
+        limits
+    }
+}
+
+/// Limits on what may be stored, counting files until they are purged.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+pub struct Quota {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_files: Option<u64>,
+}
+
+/// Who may upload to a store.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct UploadAuth {
+    /// Whether uploads need an authenticated caller.
+    #[serde(default)]
+    pub required: bool,
+    /// Scopes a token must grant.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub scopes: Vec<String>,
+    /// Claims a token must carry with exactly these values.
+    #[serde(default, skip_serializing)]
+    pub claims: HashMap<String, serde_json::Value>,
+}
+
+fn default_max_files() -> usize {
+    10
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Store {
     pub max_size: usize,
+    /// Most files accepted in a single upload request.
+    #[serde(default = "default_max_files")]
+    pub max_files: usize,
+    /// Most bytes accepted across all files of a single upload request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_total_size: Option<usize>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub restrict_content_type: Option<ContentType>,
+    #[serde(default)]
+    pub image_limits: ImageLimits,
+    #[serde(default)]
+    pub auth: UploadAuth,
+    /// Whether files are only served through signed, expiring URLs.
+    #[serde(default)]
+    pub private: bool,
+    /// Limits on everything stored in the store.
+    #[serde(default)]
+    pub quota: Quota,
+    /// Limits on what each authenticated uploader may store in the store.
+    #[serde(default)]
+    pub uploader_quota: Quota,
+    /// How often each caller may start uploads, in place of `RATE_LIMIT_UPLOAD`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<RateLimit>,
+    /// Seconds after which files that were never attached are deleted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unattached_ttl: Option<u64>,
+    #[serde(default, skip_serializing)]
+    pub backend: Option<Backend>,
 }
 
 impl Store {
@@ -30,6 +134,48 @@ impl Store {
             Err(Error::UnknownStore)
         }
     }
+
+    /// Checks that the caller may upload to the store, returning the subject to record as the
+    /// uploader.
+    pub fn authorize_upload(&self, identity: Option<&Identity>) -> Result<Option<String>> {
+        let Some(identity) = identity else {
+            if self.auth.required {
+                return Err(Error::Unauthorized);
+            }
+            return Ok(None);
+        };
+        if let Credential::Token { scopes, claims } = &identity.credential {
+            let has_scopes = self.auth.scopes.iter().all(|scope| scopes.contains(scope));
+            let has_claims =
+                self.auth
+                    .claims
+                    .iter()
+                    .all(|(name, expected)| match claims.get(name) {
+                        Some(Value::Array(values)) => values.contains(expected),
+                        Some(value) => value == expected,
+                        None => false,
+                    });
+            if !has_scopes || !has_claims {
+                return Err(Error::Forbidden);
+            }
+        }
+        Ok(Some(identity.subject.clone()))
+    }
+
+    /// Checks that the file `id` may be served, returning the `Cache-Control` to serve it with.
+    pub fn authorize_read(
+        &self,
+        store_id: &str,
+        id: &str,
+        signature: &Signature,
+    ) -> Result<String> {
+        if !self.private {
+            return Ok(CACHE_CONTROL.to_string());
+        }
+        let remaining = signing::verify(store_id, id, signature)?;
+        // Shared caches would keep serving the file after the URL expires.
+        Ok(format!("private, max-age={}", remaining))
+    }
 }
 
 static STORE_MAP: OnceCell<HashMap<String, Store>> = OnceCell::new();
@@ -44,6 +190,103 @@ pub fn load_stores() -> std::io::Result<()> {
     file.read_to_string(&mut contents)?;
 
     let stores: HashMap<String, Store> = toml::from_str(&contents).expect("Failed to parse stores");
+    if stores.values().any(|store| store.private) && URL_SIGNING_SECRET.is_none() {
+        return Err(std::io::Error::other(
+            "Private stores need URL_SIGNING_SECRET to be set",
+        ));
+    }
+    load_backends(&stores)?;
     STORE_MAP.set(stores).expect("Failed to set global stores");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn store(auth: Value) -> Store {
+        serde_json::from_value(json!({ "max_size": 1024, "auth": auth })).unwrap()
+    }
+
+    fn token(scopes: &[&str], claims: Value) -> Identity {
+        Identity {
+            subject: "user".to_string(),
+            credential: Credential::Token {
+                scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+                claims: serde_json::from_value(claims).unwrap(),
+            },
+        }
+    }
+
+    #[test]
+    fn requires_identity_when_configured() {
+        assert!(matches!(store(json!({})).authorize_upload(None), Ok(None)));
+        assert!(matches!(
+            store(json!({ "required": true })).authorize_upload(None),
+            Err(Error::Unauthorized)
+        ));
+        let identity = token(&[], json!({}));
+        assert_eq!(
+            store(json!({ "required": true }))
+                .authorize_upload(Some(&identity))
+                .unwrap()
+                .as_deref(),
+            Some("user")
+        );
+    }
+
+    #[test]
+    fn requires_every_scope() {
+        let store = store(json!({ "scopes": ["upload", "avatars"] }));
+        assert!(store
+            .authorize_upload(Some(&token(&["avatars", "upload", "other"], json!({}))))
+            .is_ok());
+        assert!(matches!(
+            store.authorize_upload(Some(&token(&["upload"], json!({})))),
+            Err(Error::Forbidden)
+        ));
+    }
+
+    #[test]
+    fn matches_claims() {
+        let store = store(json!({ "claims": { "plan": "pro", "role": "uploader" } }));
+        assert!(store
+            .authorize_upload(Some(&token(
+                &[],
+                json!({ "plan": "pro", "role": "uploader" })
+            )))
+            .is_ok());
+        // Claims listing several values match if any of them does.
+        assert!(store
+            .authorize_upload(Some(&token(
+                &[],
+                json!({ "plan": "pro", "role": ["admin", "uploader"] })
+            )))
+            .is_ok());
+        assert!(matches!(
+            store.authorize_upload(Some(&token(
+                &[],
+                json!({ "plan": "free", "role": "uploader" })
+            ))),
+            Err(Error::Forbidden)
+        ));
+        assert!(matches!(
+            store.authorize_upload(Some(&token(&[], json!({ "plan": "pro" })))),
+            Err(Error::Forbidden)
+        ));
+    }
+
+    #[test]
+    fn lets_services_and_keys_bypass_token_checks() {
+        let store = store(json!({ "required": true, "scopes": ["upload"] }));
+        for credential in [Credential::Service, Credential::ApiKey] {
+            let identity = Identity {
+                subject: "service".to_string(),
+                credential,
+            };
+            assert!(store.authorize_upload(Some(&identity)).is_ok());
+        }
+    }
+}
@@ -0,0 +1,458 @@
+use std::io::Cursor;
+use std::sync::Arc;
+
+use actix_web::web;
+use async_std::future::timeout;
+use image::codecs::avif::AvifEncoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, ImageError, ImageReader, RgbaImage};
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use tokio::sync::Semaphore;
+use webp::Encoder;
+
+use crate::environment::{TRANSFORM_CONCURRENCY, TRANSFORM_QUEUE_TIMEOUT};
+use crate::errors::{Error, Result};
+use crate::routes::serve::Resize;
+use crate::stores::ImageLimits;
+
+const DEFAULT_QUALITY: u8 = 80;
+const AVIF_SPEED: u8 = 8;
+const MAX_DPR: f64 = 4.0;
+
+lazy_static! {
+    static ref TRANSFORM_PERMITS: Arc<Semaphore> = Arc::new(Semaphore::new(*TRANSFORM_CONCURRENCY));
+}
+
+/// Runs CPU heavy image work on the blocking pool, failing with `Error::Overloaded` if no
+/// slot frees up within the queue timeout.
+pub async fn spawn<F, R>(f: F) -> Result<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let permit = timeout(
+        *TRANSFORM_QUEUE_TIMEOUT,
+        TRANSFORM_PERMITS.clone().acquire_owned(),
+    )
+    .await
+    .map_err(|_| Error::Overloaded)?
+    .map_err(|_| Error::ProcessingError)?;
+    // The permit moves into the closure, since the work carries on even if the request that
+    // started it goes away.
+    web::block(move || {
+        let _permit = permit;
+        f()
+    })
+    .await
+    .map_err(|_| Error::ProcessingError)
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    Avif,
+    Webp,
+    Jpeg,
+    Png,
+}
+
+impl ImageFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ImageFormat::Avif => "image/avif",
+            ImageFormat::Webp => "image/webp",
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::Png => "image/png",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Avif => "avif",
+            ImageFormat::Webp => "webp",
+            ImageFormat::Jpeg => "jpeg",
+            ImageFormat::Png => "png",
+        }
+    }
+}
+
+/// How the image is fitted into the requested box, following the semantics of sharp.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Fit {
+    /// Crop to cover the box exactly.
+    Cover,
+    /// Fit within the box and letterbox the remaining area.
+    Contain,
+    /// Stretch to the box, ignoring the aspect ratio.
+    Fill,
+    /// Fit within the box.
+    Inside,
+    /// Cover the box without cropping.
+    Outside,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Gravity {
+    Center,
+    North,
+    NorthEast,
+    East,
+    SouthEast,
+    South,
+    SouthWest,
+    West,
+    NorthWest,
+}
+
+impl Gravity {
+    /// Position of the anchor as fractions of the width and height.
+    fn anchor(self) -> (f64, f64) {
+        match self {
+            Gravity::Center => (0.5, 0.5),
+            Gravity::North => (0.5, 0.0),
+            Gravity::NorthEast => (1.0, 0.0),
+            Gravity::East => (1.0, 0.5),
+            Gravity::SouthEast => (1.0, 1.0),
+            Gravity::South => (0.5, 1.0),
+            Gravity::SouthWest => (0.0, 1.0),
+            Gravity::West => (0.0, 0.5),
+            Gravity::NorthWest => (0.0, 0.0),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// A fully resolved transformation of a source image.
+#[derive(Debug)]
+pub struct Transform {
+    pub width: u32,
+    pub height: u32,
+    pub crop: Option<Rect>,
+    /// Canvas the image is placed on, with the position of the image inside it.
+    pub canvas: Option<Rect>,
+    pub format: ImageFormat,
+    pub quality: u8,
+    pub lossless: bool,
+}
+
+fn dimension(value: f64) -> u32 {
+    value.round().max(1.0) as u32
+}
+
+/// Offset of a span of `inner` pixels within `outer` pixels, placed at the anchor or centred
+/// on the focal point if one is given.
+fn offset(outer: u32, inner: u32, anchor: f64, focus: Option<f64>) -> u32 {
+    let excess = outer.saturating_sub(inner) as f64;
+    let position = match focus {
+        Some(focus) => focus.clamp(0.0, 1.0) * outer as f64 - inner as f64 / 2.0,
+        None => anchor * excess,
+    };
+    position.clamp(0.0, excess).round() as u32
+}
+
+fn image_error(error: ImageError) -> Error {
+    match error {
+        ImageError::Limits(_) => Error::ImageTooLarge,
+        _ => Error::ProcessingError,
+    }
+}
+
+impl Transform {
+    pub fn plan(resize: &Resize, source: (u32, u32)) -> Option<Transform> {
+        let (source_width, source_height) = (source.0 as f64, source.1 as f64);
+        if source.0 == 0 || source.1 == 0 {
+            return None;
+        }
+        let dpr = resize.dpr.unwrap_or(1.0);
+        if !dpr.is_finite() || dpr <= 0.0 {
+            return None;
+        }
+        let dpr = dpr.min(MAX_DPR);
+        let positive = |value: Option<isize>| value.filter(|v| *v > 0).map(|v| v as f64 * dpr);
+        let (box_width, box_height, default_fit) = match (
+            positive(resize.size),
+            positive(resize.max_side),
+            positive(resize.width),
+            positive(resize.height),
+        ) {
+            (Some(size), _, _, _) => (Some(size), Some(size), Fit::Cover),
+            (_, Some(side), _, _) => (Some(side), Some(side), Fit::Inside),
+            (_, _, width, height) => (width, height, Fit::Cover),
+        };
+        // Images are never enlarged, so no box needs to be larger than the source at the
+        // highest density. This also bounds the canvas allocated for letterboxing.
+        let box_width = box_width.map(|w| w.min(source_width * MAX_DPR));
+        let box_height = box_height.map(|h| h.min(source_height * MAX_DPR));
+        let fit = resize.fit.unwrap_or(default_fit);
+        let (anchor_x, anchor_y) = resize.gravity.unwrap_or(Gravity::Center).anchor();
+
+        let mut crop = None;
+        let mut canvas = None;
+        let (width, height) = match (box_width, box_height) {
+            (None, None) => (source_width, source_height),
+            (Some(w), None) => {
+                let scale = (w / source_width).min(1.0);
+                (source_width * scale, source_height * scale)
+            }
+            (None, Some(h)) => {
+                let scale = (h / source_height).min(1.0);
+                (source_width * scale, source_height * scale)
+            }
+            (Some(w), Some(h)) => match fit {
+                Fit::Fill => (w.min(source_width), h.min(source_height)),
+                Fit::Inside | Fit::Outside => {
+                    let (x, y) = (w / source_width, h / source_height);
+                    let scale = if fit == Fit::Inside {
+                        x.min(y)
+                    } else {
+                        x.max(y)
+                    }
+                    .min(1.0);
+                    (source_width * scale, source_height * scale)
+                }
+                Fit::Cover | Fit::Contain => {
+                    // Shrink the box rather than enlarging the image.
+                    let (x, y) = (source_width / w, source_height / h);
+                    let mut shrink = if fit == Fit::Cover {
+                        x.min(y)
+                    } else {
+                        x.max(y)
+                    }
+                    .min(1.0);
+                    if fit == Fit::Contain {
+                        // However lopsided the box, letterboxing never takes more pixels than
+                        // the source has.
+                        shrink = shrink.min((source_width * source_height / (w * h)).sqrt());
+                    }
+                    let (w, h) = (w * shrink, h * shrink);
+                    let (x, y) = (w / source_width, h / source_height);
+                    let scale = if fit == Fit::Cover {
+                        x.max(y)
+                    } else {
+                        x.min(y)
+                    };
+                    let (width, height) = (
+                        dimension(source_width * scale),
+                        dimension(source_height * scale),
+                    );
+                    let (w, h) = (dimension(w), dimension(h));
+                    let (focus_x, focus_y) = match fit {
+                        Fit::Cover => (resize.focus_x, resize.focus_y),
+                        _ => (None, None),
+                    };
+                    let rect = Rect {
+                        x: offset(width.max(w), width.min(w), anchor_x, focus_x),
+                        y: offset(height.max(h), height.min(h), anchor_y, focus_y),
+                        width: w,
+                        height: h,
+                    };
+                    if (w, h) != (width, height) {
+                        if fit == Fit::Cover {
+                            crop = Some(Rect {
+                                width: w.min(width),
+                                height: h.min(height),
+                                ..rect
+                            });
+                        } else {
+                            canvas = Some(rect);
+                        }
+                    }
+                    (width as f64, height as f64)
+                }
+            },
+        };
+        let format = resize.format.unwrap_or(ImageFormat::Png);
+        Some(Transform {
+            width: dimension(width),
+            height: dimension(height),
+            crop,
+            canvas,
+            format,
+            quality: resize.quality.unwrap_or(DEFAULT_QUALITY).clamp(1, 100),
+            lossless: resize.is_lossless(),
+        })
+    }
+
+    /// Identifies the output, used as the variant cache key.
+    pub fn key(&self) -> String {
+        let mut key = format!("{}x{}", self.width, self.height);
+        if let Some(crop) = self.crop {
+            key.push_str(&format!(
+                "-c{},{},{}x{}",
+                crop.x, crop.y, crop.width, crop.height
+            ));
+        }
+        if let Some(canvas) = self.canvas {
+            key.push_str(&format!(
+                "-p{},{},{}x{}",
+                canvas.x, canvas.y, canvas.width, canvas.height
+            ));
+        }
+        if !self.lossless {
+            key.push_str(&format!("-q{}", self.quality));
+        }
+        if self.lossless && self.format == ImageFormat::Webp {
+            key.push_str("-lossless");
+        }
+        format!("{}.{}", key, self.format.extension())
+    }
+
+    pub fn apply(&self, buf: &[u8], limits: &ImageLimits) -> Result<Vec<u8>> {
+        let reader = || {
+            let mut reader = ImageReader::new(Cursor::new(buf))
+                .with_guessed_format()
+                .map_err(|_| Error::ProcessingError)?;
+            reader.limits(limits.decoder_limits());
+            Ok::<_, Error>(reader)
+        };
+        // Check the dimensions declared in the header before allocating anything.
+        let (width, height) = reader()?.into_dimensions().map_err(image_error)?;
+        limits.check(width as u64, height as u64)?;
+        let mut image = reader()?.decode().map_err(image_error)?;
+        if image.width() != self.width || image.height() != self.height {
+            image = image.resize_exact(self.width, self.height, FilterType::Gaussian);
+        }
+        if let Some(crop) = self.crop {
+            image = image.crop_imm(crop.x, crop.y, crop.width, crop.height);
+        }
+        if let Some(canvas) = self.canvas {
+            let (width, height) = (canvas.width as u64, canvas.height as u64);
+            limits.check(width, height)?;
+            if limits
+                .max_decoded_bytes
+                .is_some_and(|max| width * height * 4 > max)
+            {
+                return Err(Error::ImageTooLarge);
+            }
+            let mut background = RgbaImage::new(canvas.width, canvas.height);
+            imageops::overlay(
+                &mut background,
+                &image.to_rgba8(),
+                canvas.x as i64,
+                canvas.y as i64,
+            );
+            image = DynamicImage::ImageRgba8(background);
+        }
+        self.encode(image)
+    }
+
+    fn encode(&self, image: DynamicImage) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        match self.format {
+            ImageFormat::Avif => image
+                .write_with_encoder(AvifEncoder::new_with_speed_quality(
+                    &mut bytes,
+                    AVIF_SPEED,
+                    self.quality,
+                ))
+                .map_err(|_| Error::ProcessingError)?,
+            ImageFormat::Webp => {
+                let image = if image.color().has_alpha() {
+                    DynamicImage::ImageRgba8(image.to_rgba8())
+                } else {
+                    DynamicImage::ImageRgb8(image.to_rgb8())
+                };
+                let encoder = Encoder::from_image(&image).map_err(|_| Error::ProcessingError)?;
+                bytes = if self.lossless {
+                    encoder.encode_lossless().to_vec()
+                } else {
+                    encoder.encode(self.quality as f32).to_vec()
+                };
+            }
+            ImageFormat::Jpeg => DynamicImage::ImageRgb8(image.to_rgb8())
+                .write_with_encoder(JpegEncoder::new_with_quality(&mut bytes, self.quality))
+                .map_err(|_| Error::ProcessingError)?,
+            ImageFormat::Png => image
+                .write_with_encoder(PngEncoder::new(&mut bytes))
+                .map_err(|_| Error::ProcessingError)?,
+        }
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::web::Query;
+
+    use super::*;
+
+    fn resize(query: &str) -> Resize {
+        Query::<Resize>::from_query(query).unwrap().into_inner()
+    }
+
+    #[test]
+    fn plan_bounds_contain_canvas() {
+        let transform = Transform::plan(
+            &resize("width=1000000000&height=100&fit=contain&dpr=4"),
+            (100, 100),
+        )
+        .unwrap();
+        assert!(transform
+            .canvas
+            .is_none_or(|canvas| canvas.width <= 400 && canvas.height <= 400));
+        assert!(transform.width <= 100 && transform.height <= 100);
+
+        let transform = Transform::plan(
+            &resize("width=1000000000&height=100&fit=contain"),
+            (100, 100),
+        )
+        .unwrap();
+        assert_eq!(
+            transform.canvas,
+            Some(Rect {
+                x: 75,
+                y: 0,
+                width: 200,
+                height: 50
+            })
+        );
+
+        let transform =
+            Transform::plan(&resize("width=20000&height=5000&fit=contain"), (5000, 5000)).unwrap();
+        let canvas = transform.canvas.unwrap();
+        assert!(canvas.width as u64 * canvas.height as u64 <= 5000 * 5000);
+        assert!(transform.width <= canvas.width && transform.height <= canvas.height);
+    }
+
+    #[test]
+    fn plan_never_enlarges() {
+        for fit in ["cover", "contain", "fill", "inside", "outside"] {
+            let query = format!("width=5000&height=3000&fit={}", fit);
+            let transform = Transform::plan(&resize(&query), (200, 100)).unwrap();
+            assert!(transform.width <= 200 && transform.height <= 100, "{}", fit);
+        }
+    }
+
+    #[test]
+    fn plan_crops_cover_to_box() {
+        let transform = Transform::plan(&resize("width=50&height=50"), (200, 100)).unwrap();
+        assert_eq!((transform.width, transform.height), (100, 50));
+        assert_eq!(
+            transform.crop,
+            Some(Rect {
+                x: 25,
+                y: 0,
+                width: 50,
+                height: 50
+            })
+        );
+    }
+
+    #[test]
+    fn plan_rejects_invalid_dpr() {
+        assert!(Transform::plan(&resize("width=50&dpr=0"), (100, 100)).is_none());
+        assert!(Transform::plan(&resize("width=50"), (0, 100)).is_none());
+    }
+}
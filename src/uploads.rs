@@ -0,0 +1,170 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use async_std::fs;
+use async_std::stream::StreamExt;
+use lazy_static::lazy_static;
+use mongodb::{
+    bson::{doc, DateTime},
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    database::DATABASE,
+    environment::{MONGODB_DATABASE, UPLOAD_PATH},
+    errors::{Error, Result},
+};
+
+lazy_static! {
+    static ref ACTIVE_UPLOADS: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+pub fn get_collection() -> Collection<Upload> {
+    DATABASE
+        .get()
+        .expect("Failed to get MongoDB client")
+        .database(&MONGODB_DATABASE)
+        .collection("uploads")
+}
+
+/// A resumable upload whose contents are written to disk as they arrive.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Upload {
+    pub id: String,
+    pub store: String,
+    pub filename: Option<String>,
+    pub length: u64,
+    pub offset: u64,
+    pub created_at: DateTime,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uploader: Option<String>,
+}
+
+/// Marks an upload as being written until dropped.
+pub struct UploadLock(String);
+
+impl Drop for UploadLock {
+    fn drop(&mut self) {
+        if let Ok(mut active) = ACTIVE_UPLOADS.lock() {
+            active.remove(&self.0);
+        }
+    }
+}
+
+impl Upload {
+    pub async fn create(
+        store_id: &str,
+        length: u64,
+        filename: Option<String>,
+        uploader: Option<String>,
+    ) -> Result<Upload> {
+        let upload = Upload {
+            id: ulid::Ulid::new().to_string(),
+            store: store_id.to_string(),
+            filename,
+            length,
+            offset: 0,
+            created_at: DateTime::now(),
+            uploader,
+        };
+        fs::create_dir_all(&*UPLOAD_PATH)
+            .await
+            .map_err(|_| Error::StorageError)?;
+        fs::File::create(upload.path())
+            .await
+            .map_err(|_| Error::StorageError)?;
+        get_collection()
+            .insert_one(&upload)
+            .await
+            .map_err(|_| Error::DatabaseError)?;
+        Ok(upload)
+    }
+
+    pub async fn find(id: &str, store_id: &str) -> Result<Upload> {
+        get_collection()
+            .find_one(doc! { "id": id, "store": store_id })
+            .await
+            .map_err(|_| Error::DatabaseError)?
+            .ok_or(Error::NotFound)
+    }
+
+    pub fn path(&self) -> PathBuf {
+        PathBuf::from(&*UPLOAD_PATH).join(&self.id)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.offset == self.length
+    }
+
+    /// Claims the upload for writing or deleting, failing if another request already has.
+    pub fn lock(&self) -> Result<UploadLock> {
+        let mut active = ACTIVE_UPLOADS.lock().map_err(|_| Error::UnknownError)?;
+        if !active.insert(self.id.clone()) {
+            return Err(Error::UploadLocked);
+        }
+        Ok(UploadLock(self.id.clone()))
+    }
+
+    /// Records that the contents were written up to `offset`.
+    pub async fn advance(&mut self, offset: u64) -> Result<()> {
+        let result = get_collection()
+            .update_one(
+                doc! { "id": &self.id, "offset": self.offset as i64 },
+                doc! { "$set": { "offset": offset as i64 } },
+            )
+            .await
+            .map_err(|_| Error::DatabaseError)?;
+        if result.matched_count == 0 {
+            return Err(Error::OffsetMismatch);
+        }
+        self.offset = offset;
+        Ok(())
+    }
+
+    pub async fn delete(self) -> Result<()> {
+        match fs::remove_file(self.path()).await {
+            Ok(()) => {}
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+            Err(_) => return Err(Error::StorageError),
+        }
+        get_collection()
+            .delete_one(doc! { "id": &self.id })
+            .await
+            .map_err(|_| Error::DatabaseError)?;
+        Ok(())
+    }
+
+    /// Deletes every unfinished upload started by `uploader`, returning how many were deleted.
+    pub async fn delete_by_uploader(uploader: &str) -> Result<u64> {
+        let mut cursor = get_collection()
+            .find(doc! { "uploader": uploader })
+            .await
+            .map_err(|_| Error::DatabaseError)?;
+        let mut count = 0;
+        while let Some(upload) = cursor.next().await {
+            upload.map_err(|_| Error::DatabaseError)?.delete().await?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Deletes uploads started longer than `ttl` ago that never completed, returning how
+    /// many were deleted.
+    pub async fn expire(ttl: Duration) -> Result<u64> {
+        let cutoff = DateTime::from_system_time(SystemTime::now() - ttl);
+        let mut cursor = get_collection()
+            .find(doc! { "createdAt": { "$lt": cutoff } })
+            .await
+            .map_err(|_| Error::DatabaseError)?;
+        let mut count = 0;
+        while let Some(upload) = cursor.next().await {
+            upload.map_err(|_| Error::DatabaseError)?.delete().await?;
+            count += 1;
+        }
+        Ok(count)
+    }
+}
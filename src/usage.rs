@@ -0,0 +1,226 @@
+use mongodb::{bson::doc, Collection};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    database::{is_duplicate_key, DATABASE},
+    environment::MONGODB_DATABASE,
+    errors::{Error, QuotaKind, Result},
+    stores::{Quota, Store},
+};
+
+pub fn get_collection() -> Collection<Usage> {
+    DATABASE
+        .get()
+        .expect("Failed to get MongoDB client")
+        .database(&MONGODB_DATABASE)
+        .collection("usage")
+}
+
+/// Counts what a store holds, as a whole or for one uploader.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Usage {
+    /// The store, followed by the uploader if the counter is theirs. Keying counters by id
+    /// keeps concurrent upserts from creating duplicates.
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub store: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uploader: Option<String>,
+    pub bytes: i64,
+    pub files: i64,
+}
+
+fn usage_id(store_id: &str, uploader: Option<&str>) -> String {
+    match uploader {
+        Some(uploader) => format!("{}/{}", store_id, uploader),
+        None => store_id.to_string(),
+    }
+}
+
+impl Usage {
+    pub async fn get(store_id: &str, uploader: Option<&str>) -> Result<Usage> {
+        let id = usage_id(store_id, uploader);
+        Ok(get_collection()
+            .find_one(doc! { "_id": &id })
+            .await
+            .map_err(|_| Error::DatabaseError)?
+            .unwrap_or_else(|| Usage {
+                id,
+                store: store_id.to_string(),
+                uploader: uploader.map(str::to_string),
+                ..Default::default()
+            }))
+    }
+
+    /// Fails with the limit that adding a file of `size` bytes would exceed, if any.
+    fn check_quota(&self, quota: &Quota, size: u64) -> Result<()> {
+        let (bytes, files) = (self.bytes.max(0) as u64, self.files.max(0) as u64);
+        if let Some(limit) = quota.max_files.filter(|limit| files + 1 > *limit) {
+            return Err(Error::QuotaExceeded {
+                kind: QuotaKind::Files,
+                used: files,
+                limit,
+            });
+        }
+        if let Some(limit) = quota.max_bytes.filter(|limit| bytes + size > *limit) {
+            return Err(Error::QuotaExceeded {
+                kind: QuotaKind::Bytes,
+                used: bytes,
+                limit,
+            });
+        }
+        Ok(())
+    }
+
+    /// Atomically counts a file of `size` bytes unless that would exceed `quota`.
+    async fn add(store_id: &str, uploader: Option<&str>, size: u64, quota: &Quota) -> Result<()> {
+        // An upsert only applies the id of the filter, so check the limits against an empty
+        // counter here.
+        Usage::default().check_quota(quota, size)?;
+        let id = usage_id(store_id, uploader);
+        let mut filter = doc! { "_id": &id };
+        if let Some(max_files) = quota.max_files {
+            filter.insert("files", doc! { "$lte": max_files as i64 - 1 });
+        }
+        if let Some(max_bytes) = quota.max_bytes {
+            filter.insert("bytes", doc! { "$lte": max_bytes as i64 - size as i64 });
+        }
+        let update = doc! {
+            "$inc": { "bytes": size as i64, "files": 1 },
+            "$setOnInsert": { "store": store_id, "uploader": uploader },
+        };
+        // When the counter exists but is over quota, the upsert collides with it. A collision
+        // can also come from a concurrent upsert creating the counter, so try once more.
+        for _ in 0..2 {
+            match get_collection()
+                .update_one(filter.clone(), update.clone())
+                .upsert(true)
+                .await
+            {
+                Ok(_) => return Ok(()),
+                Err(error) if is_duplicate_key(&error) => {}
+                Err(_) => return Err(Error::DatabaseError),
+            }
+        }
+        Usage::get(store_id, uploader)
+            .await?
+            .check_quota(quota, size)?;
+        Err(Error::DatabaseError)
+    }
+
+    async fn remove(store_id: &str, uploader: Option<&str>, size: u64) -> Result<()> {
+        // Files stored before usage was counted would otherwise take counters below zero.
+        get_collection()
+            .update_one(
+                doc! {
+                    "_id": usage_id(store_id, uploader),
+                    "bytes": { "$gte": size as i64 },
+                    "files": { "$gte": 1 },
+                },
+                doc! { "$inc": { "bytes": -(size as i64), "files": -1 } },
+            )
+            .await
+            .map_err(|_| Error::DatabaseError)?;
+        Ok(())
+    }
+
+    /// Checks whether a file of `size` bytes fits the quotas of the store and of its uploader
+    /// without counting it, to turn away uploads early.
+    pub async fn check(
+        store: &Store,
+        store_id: &str,
+        uploader: Option<&str>,
+        size: u64,
+    ) -> Result<()> {
+        Usage::get(store_id, None)
+            .await?
+            .check_quota(&store.quota, size)?;
+        if let Some(uploader) = uploader {
+            Usage::get(store_id, Some(uploader))
+                .await?
+                .check_quota(&store.uploader_quota, size)?;
+        }
+        Ok(())
+    }
+
+    /// Counts a new file against the quotas of the store and of its uploader, counting it
+    /// against neither if either would be exceeded.
+    pub async fn reserve(
+        store: &Store,
+        store_id: &str,
+        uploader: Option<&str>,
+        size: u64,
+    ) -> Result<()> {
+        Usage::add(store_id, None, size, &store.quota).await?;
+        if let Some(uploader) = uploader {
+            if let Err(error) =
+                Usage::add(store_id, Some(uploader), size, &store.uploader_quota).await
+            {
+                Usage::remove(store_id, None, size).await.ok();
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    /// Stops counting a file once it is gone.
+    pub async fn release(store_id: &str, uploader: Option<&str>, size: u64) -> Result<()> {
+        Usage::remove(store_id, None, size).await?;
+        if let Some(uploader) = uploader {
+            Usage::remove(store_id, Some(uploader), size).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(bytes: i64, files: i64) -> Usage {
+        Usage {
+            bytes,
+            files,
+            ..Default::default()
+        }
+    }
+
+    const QUOTA: Quota = Quota {
+        max_bytes: Some(100),
+        max_files: Some(2),
+    };
+
+    #[test]
+    fn allows_files_within_quota() {
+        assert!(usage(0, 0).check_quota(&QUOTA, 100).is_ok());
+        assert!(usage(50, 1).check_quota(&QUOTA, 50).is_ok());
+        assert!(usage(1000, 1000)
+            .check_quota(&Quota::default(), 1000)
+            .is_ok());
+    }
+
+    #[test]
+    fn reports_exceeded_limit() {
+        assert!(matches!(
+            usage(50, 1).check_quota(&QUOTA, 51),
+            Err(Error::QuotaExceeded {
+                kind: QuotaKind::Bytes,
+                used: 50,
+                limit: 100
+            })
+        ));
+        assert!(matches!(
+            usage(10, 2).check_quota(&QUOTA, 1),
+            Err(Error::QuotaExceeded {
+                kind: QuotaKind::Files,
+                used: 2,
+                limit: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn treats_negative_counters_as_empty() {
+        assert!(usage(-10, -1).check_quota(&QUOTA, 100).is_ok());
+    }
+}
@@ -1,18 +1,14 @@
-use std::io::Cursor;
 use std::io::Write;
 use std::time::Duration;
 
-use image::imageops::FilterType;
-use image::ImageError;
-use image::ImageReader;
 use lazy_static::lazy_static;
 use mime::Mime;
 use reqwest::{header::CONTENT_TYPE, Client, Response};
 use serde::Serialize;
 use tempfile::NamedTempFile;
 use validator::Validate;
-use webp::Encoder;
 
+use crate::constants::USER_AGENT;
 use crate::metadata::Metadata;
 use crate::scraper::TwitchChannel;
 
@@ -76,30 +72,48 @@ pub enum Special {
 
 lazy_static! {
     static ref CLIENT: Client = reqwest::Client::builder()
-        .user_agent(
-            "Mozilla/5.0 (compatible; NextflowCDN/1.0; +https://github.com/Nextflow-Cloud/cdn)"
-        )
-        .timeout(Duration::from_secs(2))
+        .user_agent(USER_AGENT)
         .build()
         .expect("Failed to build reqwest client");
 }
 
-pub async fn fetch(url: &str) -> Result<(Response, Mime), Error> {
+/// How `fetch` requests a URL.
+pub struct FetchOptions {
+    /// Time allowed for the whole response, including its body.
+    pub timeout: Duration,
+    /// Whether to fail if the response does not say what it contains. Otherwise, it is
+    /// treated as arbitrary bytes.
+    pub require_content_type: bool,
+}
+
+impl Default for FetchOptions {
+    fn default() -> FetchOptions {
+        FetchOptions {
+            timeout: Duration::from_secs(2),
+            require_content_type: true,
+        }
+    }
+}
+
+pub async fn fetch(url: &str, options: &FetchOptions) -> Result<(Response, Mime), Error> {
     let resp = CLIENT
         .get(url)
+        .timeout(options.timeout)
         .send()
         .await
         .map_err(|_| Error::InternalRequestFailed)?;
     if !resp.status().is_success() {
         return Err(Error::RequestFailed);
     }
-    let content_type = resp
-        .headers()
-        .get(CONTENT_TYPE)
-        .ok_or(Error::MissingContentType)?
-        .to_str()
-        .map_err(|_| Error::InternalRequestFailed)?;
+    let Some(content_type) = resp.headers().get(CONTENT_TYPE) else {
+        if options.require_content_type {
+            return Err(Error::MissingContentType);
+        }
+        return Ok((resp, mime::APPLICATION_OCTET_STREAM));
+    };
     let mime: mime::Mime = content_type
+        .to_str()
+        .map_err(|_| Error::InternalRequestFailed)?
         .parse()
         .map_err(|_| Error::InternalRequestFailed)?;
     Ok((resp, mime))
@@ -138,13 +152,3 @@ pub async fn get_media_size(resp: Response, mime: Mime) -> Result<(isize, isize)
         _ => unreachable!(),
     }
 }
-
-pub async fn try_resize(buf: &Vec<u8>, width: u32, height: u32) -> Result<Vec<u8>, ImageError> {
-    let image = ImageReader::new(Cursor::new(buf))
-        .with_guessed_format()?
-        .decode()?
-        .resize_exact(width, height, FilterType::Gaussian);
-    let encoder = Encoder::from_image(&image).expect("Failed to create webp encoder");
-    let bytes = encoder.encode_lossless().to_vec();
-    Ok(bytes)
-}